@@ -1,34 +1,126 @@
-use crate::target::{TargetId, TargetInfo};
+use crate::target::{
+    TargetCapacity, TargetChange, TargetChangeOp, TargetId, TargetInfo, TargetLocation,
+    TransitionError,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, fmt::Display};
 use uuid::Uuid;
 
 /// Cluster map changelog.
-#[derive(Serialize, Deserialize, Clone)]
+///
+/// Changelog records operations on targets, new target states are derived when
+/// the changelog is applied to the previous cluster map.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ChangeLog {
     /// Version of this change
     pub version: ClusterMapVersion,
-    /// Targets that changed.
-    pub targets: Vec<TargetInfo>,
+    /// Operations on targets, applied in order.
+    pub changes: Vec<TargetChange>,
     /// Human readable information for this changelog entry
     pub info: String,
 }
 
 impl ChangeLog {
-    pub fn new(version: ClusterMapVersion, targets: Vec<TargetInfo>, info: String) -> ChangeLog {
+    pub fn new(version: ClusterMapVersion, changes: Vec<TargetChange>, info: String) -> ChangeLog {
         ChangeLog {
             version,
-            targets,
+            changes,
             info,
         }
     }
 }
 
+/// Builder of the next changelog of a cluster map.
+///
+/// Version is computed from the operations: major if any operation affects data
+/// placement, minor otherwise.
+pub struct ChangeLogBuilder<'a> {
+    map: &'a ClusterMap,
+    changes: Vec<TargetChange>,
+    info: String,
+}
+
+impl<'a> ChangeLogBuilder<'a> {
+    /// Add an operation on target `uuid`.
+    pub fn add(mut self, uuid: Uuid, op: TargetChangeOp) -> Self {
+        self.changes.push(TargetChange::new(uuid, op));
+        self
+    }
+
+    /// Set human readable information.
+    pub fn info(mut self, info: String) -> Self {
+        self.info = info;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Next version of the map after these operations.
+    pub fn version(&self) -> ClusterMapVersion {
+        if self.changes.iter().any(|change| change.op.is_major()) {
+            self.map.version.next_major()
+        } else {
+            self.map.version.next_minor()
+        }
+    }
+
+    /// Build the changelog, it is validated against the map.
+    pub fn build(self) -> Result<ChangeLog, ChangeLogError> {
+        let log = ChangeLog::new(self.version(), self.changes, self.info);
+        self.map.clone().apply_in_place(&log)?;
+        Ok(log)
+    }
+}
+
+impl Display for ChangeLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.version, self.info)?;
+        for change in &self.changes {
+            write!(f, "\n  {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Violation found when applying changelog to cluster map.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ChangeLogError {
+    /// Changelog version is not next of map version, `(map version, log version)`
+    #[error("changelog {1} doesn't follow cluster map {0}")]
+    VersionGap(ClusterMapVersion, ClusterMapVersion),
+    /// Created target already exists, `(uuid)`
+    #[error("target {0} already exists")]
+    TargetExists(Uuid),
+    /// Target id is used by another target, `(id, uuid of other target)`
+    #[error("target id {0} is used by target {1}")]
+    IdCollision(TargetId, Uuid),
+    /// Target doesn't exist in cluster map, `(uuid)`
+    #[error("target {0} doesn't exist")]
+    UnknownTarget(Uuid),
+    /// Target can't be removed, `(id, state)`
+    #[error("target {0} can't be removed in state {1}")]
+    NotRemovable(TargetId, &'static str),
+    /// Operation can't be applied to target state, `(uuid, error)`
+    #[error("target {0}: {1}")]
+    Transition(Uuid, TransitionError),
+    /// Minor version contains major operation, `(log version, uuid, op)`
+    #[error("changelog {0} is a minor change but target {1}: {2} is major")]
+    MajorChangeInMinorVersion(ClusterMapVersion, Uuid, &'static str),
+    /// Conflicting operations on the same target in one changelog, `(uuid, op)`
+    #[error("target {0}: conflicting {1} in changelog")]
+    ConflictingChange(Uuid, &'static str),
+}
+
 /// Version of cluster map.
 ///
 /// Version consists of two parts: major and minor.
 /// Major number incrase at major change (targets IN/OUT).
 /// Minor number reset to 0 at major change and increase at minor change (targets UP/DOWN).
+///
+/// See `TargetChangeOp::is_major` for the full list, changelogs bump minor version
+/// must not contain major changes, so placements cached by major version stay valid.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
 pub struct ClusterMapVersion {
     /// major version
@@ -74,15 +166,39 @@ impl Display for ClusterMapVersion {
     }
 }
 
+/// Raft position where a cluster map is committed.
+///
+/// Revisions are ordered by raft log index, replicas and watches
+/// resume from a revision and only need entries after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
+pub struct Revision {
+    /// raft log index
+    pub index: u64,
+    /// term of the raft log entry
+    pub term: u64,
+}
+
+impl Revision {
+    pub fn new(index: u64, term: u64) -> Self {
+        Revision { index, term }
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}@{}", self.index, self.term))
+    }
+}
+
 /// Cluster map of specific version.
 /// ClusterMap should be read only, any modifications on current map will generate a new ClusterMap.
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct ClusterMap {
     pub version: ClusterMapVersion,
     pub uuid_map: BTreeMap<Uuid, TargetId>,
     pub targets: BTreeMap<TargetId, TargetInfo>,
-    /// revision in etcd, can use this revision to watch changes
-    pub revision: i64,
+    /// raft revision where this map is committed, can use this revision to resume watch
+    pub revision: Revision,
 }
 
 impl ClusterMap {
@@ -92,7 +208,7 @@ impl ClusterMap {
             version: ClusterMapVersion::new(0, 0),
             uuid_map: BTreeMap::new(),
             targets: BTreeMap::new(),
-            revision: 0,
+            revision: Revision::default(),
         }
     }
 
@@ -111,78 +227,594 @@ impl ClusterMap {
         self.targets.iter().next_back().map(|(&id, _)| id)
     }
 
-    /// Apply changelog to current cluster map to generate next cluster map.
-    pub fn apply_change(&self, log: &ChangeLog, revision: Option<i64>) -> ClusterMap {
-        // check change log
-        assert!(self.version.is_next(&log.version));
-        for target in &log.targets {
-            let id = target
-                .get_id()
-                .expect("target in changelog shouldn't be Init state!!");
-            let uuid = target.uuid;
-            match self.uuid_map.get(&uuid) {
-                Some(&old_id) => assert_eq!(old_id, id),
-                None => {
-                    assert!(self.targets.get(&id).is_none());
-                }
-            }
+    /// Get targets whose location is under `path`, eg: `/region/zone/rack`.
+    pub fn targets_under<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a TargetInfo> {
+        self.targets.values().filter(move |target| {
+            let full = target.location.format_path();
+            full == path || full.starts_with(&format!("{}/", path))
+        })
+    }
+
+    /// Start building the next changelog of this map.
+    pub fn change_builder(&self) -> ChangeLogBuilder<'_> {
+        ChangeLogBuilder {
+            map: self,
+            changes: Vec::new(),
+            info: String::new(),
         }
+    }
 
-        let version = log.version;
-        let mut uuid_map = self.uuid_map.clone();
-        let mut targets = self.targets.clone();
+    /// Changelog with a single operation on target `id`.
+    fn single_change(&self, id: TargetId, op: TargetChangeOp, info: String) -> Option<ChangeLog> {
+        let target = self.get_target(id)?;
+        self.change_builder()
+            .add(target.uuid, op)
+            .info(info)
+            .build()
+            .ok()
+    }
 
-        for target in &log.targets {
-            let target_id = target.get_id().unwrap();
-            uuid_map.insert(target.uuid, target_id);
-            targets.insert(target_id, target.clone());
+    /// Changelog that moves target `id` to `location`.
+    ///
+    /// Return None if target doesn't exist or it's already at `location`.
+    pub fn move_target(&self, id: TargetId, location: TargetLocation) -> Option<ChangeLog> {
+        let target = self.get_target(id)?;
+        if target.location == location {
+            return None;
         }
+        let info = format!(
+            "move target {} from {} to {}",
+            id, target.location, location
+        );
+        self.single_change(id, TargetChangeOp::Move(location), info)
+    }
 
-        // check new target map
-        for (target_id, target) in &targets {
-            assert_eq!(Some(*target_id), target.get_id());
-            assert_eq!(uuid_map.get(&target.uuid), Some(target_id));
+    /// Changelog that sets placement weight of target `id`.
+    ///
+    /// Return None if target doesn't exist or weight is unchanged.
+    pub fn set_weight(&self, id: TargetId, weight: u32) -> Option<ChangeLog> {
+        let target = self.get_target(id)?;
+        if target.weight == weight {
+            return None;
         }
+        let info = format!(
+            "set weight of target {} from {} to {}",
+            id, target.weight, weight
+        );
+        self.single_change(id, TargetChangeOp::SetWeight(weight), info)
+    }
 
-        ClusterMap {
-            version,
-            uuid_map,
-            targets,
-            revision: revision.unwrap_or(0),
+    /// Changelog that records capacity reported by target `id`.
+    ///
+    /// Return None if target doesn't exist or capacity doesn't need update,
+    /// so periodic reports don't bump map version, see `TargetCapacity::needs_update`.
+    pub fn report_capacity(&self, id: TargetId, capacity: TargetCapacity) -> Option<ChangeLog> {
+        let target = self.get_target(id)?;
+        if !target.capacity.needs_update(&capacity) {
+            return None;
+        }
+        let info = format!(
+            "target {} reports capacity {}/{} bytes",
+            id, capacity.used, capacity.total
+        );
+        self.single_change(id, TargetChangeOp::Capacity(capacity), info)
+    }
+
+    /// Changelog that marks IN target `id` as DRAIN.
+    ///
+    /// Return None if target doesn't exist or is not IN.
+    pub fn drain_target(&self, id: TargetId) -> Option<ChangeLog> {
+        let info = format!("drain target {}", id);
+        self.single_change(id, TargetChangeOp::Drain, info)
+    }
+
+    /// Changelog that sets maintenance mode of all targets under location `path`,
+    /// eg: `/region/zone/rack/host` for all targets on a host.
+    ///
+    /// Return None if no target changed.
+    pub fn set_maintenance(&self, path: &str, maintenance: bool) -> Option<ChangeLog> {
+        let info = if maintenance {
+            format!("enter maintenance: {}", path)
+        } else {
+            format!("leave maintenance: {}", path)
+        };
+        let builder = self
+            .targets_under(path)
+            .filter(|target| target.maintenance != maintenance)
+            .fold(self.change_builder().info(info), |builder, target| {
+                builder.add(target.uuid, TargetChangeOp::Maintenance(maintenance))
+            });
+        if builder.is_empty() {
+            return None;
+        }
+        builder.build().ok()
+    }
+
+    /// Changelog that marks targets `down` DOWN and `out` OUT, eg: they stop sending heartbeat.
+    ///
+    /// Return None if no target exists.
+    pub fn mark_down(&self, down: &[TargetId], out: &[TargetId]) -> Option<ChangeLog> {
+        let info = format!("mark down {:?}, out {:?}", down, out);
+        let ops = down
+            .iter()
+            .map(|&id| (id, TargetChangeOp::Down))
+            .chain(out.iter().map(|&id| (id, TargetChangeOp::Out)));
+        let builder = ops
+            .filter_map(|(id, op)| Some((self.get_target(id)?.uuid, op)))
+            .fold(self.change_builder().info(info), |builder, (uuid, op)| {
+                builder.add(uuid, op)
+            });
+        if builder.is_empty() {
+            return None;
         }
+        builder.build().ok()
+    }
+
+    /// Changelog that removes DownOut target `id` from cluster map permanently.
+    ///
+    /// Caller should make sure all data on the target has been migrated.
+    /// Return None if target doesn't exist or is not DownOut.
+    pub fn remove_target(&self, id: TargetId) -> Option<ChangeLog> {
+        let info = format!("remove target {}", id);
+        self.single_change(id, TargetChangeOp::Remove, info)
     }
 
-    /// Apply all changelogs to generate new cluster map
-    pub fn apply_all(&self, logs: &[ChangeLog], revision: Option<i64>) -> Option<ClusterMap> {
+    /// Apply changelog to current cluster map to generate next cluster map.
+    ///
+    /// Changelog is validated first, current map is unchanged on error.
+    pub fn apply_change(
+        &self,
+        log: &ChangeLog,
+        revision: Option<Revision>,
+    ) -> Result<ClusterMap, ChangeLogError> {
+        let mut map = self.clone();
+        map.apply_in_place(log)?;
+        map.revision = revision.unwrap_or_default();
+        Ok(map)
+    }
+
+    /// Apply all changelogs to generate new cluster map.
+    ///
+    /// Each changelog is validated the same as `apply_change`.
+    /// Return None if no changelog is newer than current map.
+    pub fn apply_all(
+        &self,
+        logs: &[ChangeLog],
+        revision: Option<Revision>,
+    ) -> Result<Option<ClusterMap>, ChangeLogError> {
         let mut logs = logs
             .iter()
             .skip_while(|log| log.version <= self.version)
             .peekable();
         if logs.peek().is_none() {
-            return None;
+            return Ok(None);
         }
 
-        let mut targets = self.targets.clone();
-        let mut uuid_map = self.uuid_map.clone();
-        let mut prev_version = self.version;
+        let mut map = self.clone();
         for log in logs {
-            // log version should be continuous
-            assert!(prev_version.is_next(&log.version));
-            prev_version = log.version;
-
-            // add targets
-            for target in &log.targets {
-                let target_id = target.get_id().unwrap();
-                targets.insert(target_id, target.clone());
-                uuid_map.insert(target.uuid, target_id);
+            map.apply_in_place(log)?;
+        }
+        map.revision = revision.unwrap_or_default();
+        Ok(Some(map))
+    }
+
+    /// Validate changelog and apply it to self.
+    ///
+    /// Self may be partially modified on error, callers should apply on a copy.
+    fn apply_in_place(&mut self, log: &ChangeLog) -> Result<(), ChangeLogError> {
+        // check change log
+        if !self.version.is_next(&log.version) {
+            return Err(ChangeLogError::VersionGap(self.version, log.version));
+        }
+        if log.version.major == self.version.major {
+            if let Some(change) = log.changes.iter().find(|change| change.op.is_major()) {
+                return Err(ChangeLogError::MajorChangeInMinorVersion(
+                    log.version,
+                    change.uuid,
+                    change.op.to_str(),
+                ));
             }
         }
+        for (i, change) in log.changes.iter().enumerate() {
+            let conflict = log.changes[..i]
+                .iter()
+                .any(|prev| prev.uuid == change.uuid && prev.op.conflicts_with(&change.op));
+            if conflict {
+                return Err(ChangeLogError::ConflictingChange(
+                    change.uuid,
+                    change.op.to_str(),
+                ));
+            }
+        }
+
+        for change in &log.changes {
+            self.apply_target_change(change)?;
+        }
+        self.version = log.version;
+        Ok(())
+    }
+
+    /// Apply one operation, derive new state of the target.
+    fn apply_target_change(&mut self, change: &TargetChange) -> Result<(), ChangeLogError> {
+        let uuid = change.uuid;
+        let id = match self.uuid_map.get(&uuid) {
+            Some(&id) => id,
+            None => {
+                if let TargetChangeOp::Create(id, url, location, capacity) = &change.op {
+                    if let Some(other) = self.targets.get(id) {
+                        return Err(ChangeLogError::IdCollision(*id, other.uuid));
+                    }
+                    let target =
+                        TargetInfo::new(uuid, *id, *url, false, location.clone(), *capacity);
+                    self.uuid_map.insert(uuid, *id);
+                    self.targets.insert(*id, target);
+                    return Ok(());
+                }
+                return Err(ChangeLogError::UnknownTarget(uuid));
+            }
+        };
 
-        Some(ClusterMap {
-            version: prev_version,
-            uuid_map,
-            targets,
-            revision: revision.unwrap_or(0),
+        let target = &self.targets[&id];
+        let transition = |res: Result<TargetInfo, TransitionError>| {
+            res.map_err(|e| ChangeLogError::Transition(uuid, e))
+        };
+        let new_target = match &change.op {
+            TargetChangeOp::Create(..) => return Err(ChangeLogError::TargetExists(uuid)),
+            TargetChangeOp::Up(url) => transition(target.up(*url))?,
+            TargetChangeOp::Down => transition(target.down())?,
+            TargetChangeOp::In => transition(target.add_in())?,
+            TargetChangeOp::Out => transition(target.remove_out())?,
+            TargetChangeOp::Drain => transition(target.drain())?,
+            TargetChangeOp::Move(location) => target.move_to(location.clone()),
+            TargetChangeOp::SetWeight(weight) => target.set_weight(*weight),
+            TargetChangeOp::Capacity(capacity) => target.report_capacity(*capacity),
+            TargetChangeOp::Maintenance(on) => target.set_maintenance(*on),
+            TargetChangeOp::Remove => {
+                if !target.is_removable() {
+                    return Err(ChangeLogError::NotRemovable(id, target.state.to_str()));
+                }
+                self.targets.remove(&id);
+                self.uuid_map.remove(&uuid);
+                return Ok(());
+            }
+        };
+        self.targets.insert(id, new_target);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::TargetState;
+    use proptest::prelude::*;
+    use std::net::SocketAddr;
+
+    const POOL: u32 = 4;
+
+    fn uuid(i: u32) -> Uuid {
+        Uuid::from_u128(i as u128 + 1)
+    }
+
+    fn url(i: u32) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i as u8], 8000))
+    }
+
+    fn create(i: u32, id: TargetId) -> TargetChange {
+        let location = TargetLocation::with_host(&format!("host{}", i), "dev").unwrap();
+        let op = TargetChangeOp::Create(id, None, location, TargetCapacity::default());
+        TargetChange::new(uuid(i), op)
+    }
+
+    fn change(i: u32, op: TargetChangeOp) -> TargetChange {
+        TargetChange::new(uuid(i), op)
+    }
+
+    /// Target `i` usually keeps id `i`, sometimes collides with another target.
+    fn arb_change() -> impl Strategy<Value = TargetChange> {
+        (0..POOL, prop::bool::weighted(0.05), 0..12u8, 0..3u32).prop_map(|(i, remap, op, arg)| {
+            let op = match op {
+                0 | 1 => return create(i, if remap { (i + 1) % POOL } else { i }),
+                2 => TargetChangeOp::Up(url(arg)),
+                3 => TargetChangeOp::Down,
+                4 => TargetChangeOp::In,
+                5 => TargetChangeOp::Out,
+                6 => TargetChangeOp::Drain,
+                7 => TargetChangeOp::Move(TargetLocation::with_host("moved", "dev").unwrap()),
+                8 => TargetChangeOp::SetWeight(arg),
+                9 => TargetChangeOp::Maintenance(arg == 0),
+                10 => TargetChangeOp::Capacity(TargetCapacity::new(arg as u64, 0)),
+                _ => TargetChangeOp::Remove,
+            };
+            change(i, op)
         })
     }
+
+    /// Changelogs with mostly continuous versions.
+    fn arb_logs() -> impl Strategy<Value = Vec<ChangeLog>> {
+        let log = (0..20u8, prop::collection::vec(arb_change(), 0..4));
+        prop::collection::vec(log, 0..12).prop_map(|logs| {
+            let mut version = ClusterMapVersion::default();
+            logs.into_iter()
+                .map(|(kind, changes)| {
+                    let major = changes.iter().any(|change| change.op.is_major());
+                    version = match kind {
+                        0 => version.next_minor().next_minor(),
+                        1 => version.next_minor(),
+                        _ if major => version.next_major(),
+                        2..=16 => version.next_minor(),
+                        _ => version.next_major(),
+                    };
+                    ChangeLog::new(version, changes, String::new())
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_apply_all_equals_apply_change(logs in arb_logs()) {
+            let initial = ClusterMap::new_initial();
+            let expect = logs
+                .iter()
+                .try_fold(initial.clone(), |map, log| map.apply_change(log, Some(Revision::new(7, 1))));
+            let got = initial.apply_all(&logs, Some(Revision::new(7, 1)));
+            match (expect, got) {
+                (Ok(expect), Ok(Some(got))) => prop_assert_eq!(expect, got),
+                (Ok(expect), Ok(None)) => {
+                    prop_assert!(logs.is_empty());
+                    prop_assert_eq!(expect, initial);
+                }
+                (Err(expect), Err(got)) => prop_assert_eq!(expect, got),
+                (expect, got) => prop_assert!(false, "{:?} != {:?}", expect, got),
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_change() {
+        let v1 = ClusterMapVersion::new(0, 1);
+        let log = ChangeLog::new(
+            v1,
+            vec![create(0, 0), change(0, TargetChangeOp::Up(url(1)))],
+            "create".to_owned(),
+        );
+        let map = ClusterMap::new_initial().apply_change(&log, None).unwrap();
+        assert_eq!(
+            map.get_target(0).unwrap().state,
+            TargetState::UpOut(0, url(1))
+        );
+
+        let v2 = v1.next_major();
+        let log = ChangeLog::new(v2, vec![change(0, TargetChangeOp::In)], String::new());
+        let map = map.apply_change(&log, None).unwrap();
+        assert_eq!(
+            map.get_target_by_uuid(&uuid(0)).unwrap().state,
+            TargetState::UpIn(0, url(1))
+        );
+
+        let v3 = v2.next_minor();
+        let v4 = v2.next_major();
+        let cases = vec![
+            (
+                ChangeLog::new(v3.next_minor(), vec![], String::new()),
+                ChangeLogError::VersionGap(v2, v3.next_minor()),
+            ),
+            (
+                ChangeLog::new(v3, vec![create(0, 1)], String::new()),
+                ChangeLogError::TargetExists(uuid(0)),
+            ),
+            (
+                ChangeLog::new(v3, vec![create(1, 0)], String::new()),
+                ChangeLogError::IdCollision(0, uuid(0)),
+            ),
+            (
+                ChangeLog::new(v3, vec![create(1, 1), create(2, 1)], String::new()),
+                ChangeLogError::IdCollision(1, uuid(1)),
+            ),
+            (
+                ChangeLog::new(v3, vec![change(1, TargetChangeOp::Down)], String::new()),
+                ChangeLogError::UnknownTarget(uuid(1)),
+            ),
+            (
+                ChangeLog::new(v4, vec![change(0, TargetChangeOp::Remove)], String::new()),
+                ChangeLogError::NotRemovable(0, "UpIn"),
+            ),
+            (
+                ChangeLog::new(v4, vec![change(0, TargetChangeOp::In)], String::new()),
+                ChangeLogError::Transition(uuid(0), TransitionError::NoOp("UpIn")),
+            ),
+            (
+                ChangeLog::new(
+                    v4,
+                    vec![
+                        change(0, TargetChangeOp::Out),
+                        change(0, TargetChangeOp::In),
+                    ],
+                    String::new(),
+                ),
+                ChangeLogError::ConflictingChange(uuid(0), "in"),
+            ),
+            (
+                ChangeLog::new(v3, vec![change(0, TargetChangeOp::Drain)], String::new()),
+                ChangeLogError::MajorChangeInMinorVersion(v3, uuid(0), "drain"),
+            ),
+        ];
+        for (log, expect) in cases {
+            assert_eq!(map.apply_change(&log, None).unwrap_err(), expect);
+            assert_eq!(map.apply_all(&[log], None).unwrap_err(), expect);
+        }
+
+        let log = map
+            .change_builder()
+            .add(uuid(0), TargetChangeOp::Out)
+            .add(uuid(0), TargetChangeOp::Down)
+            .add(uuid(0), TargetChangeOp::Remove)
+            .build()
+            .unwrap();
+        assert_eq!(log.version, v4);
+        let removed = map.apply_change(&log, None).unwrap();
+        assert!(removed.targets.is_empty() && removed.uuid_map.is_empty());
+    }
+
+    #[test]
+    fn test_change_log_error() {
+        let v1 = ClusterMapVersion::new(0, 1);
+        let initial = ClusterMap::new_initial();
+        let log1 = ChangeLog::new(
+            v1,
+            vec![create(0, 0), change(0, TargetChangeOp::Up(url(1)))],
+            String::new(),
+        );
+        let log3 = ChangeLog::new(v1.next_minor().next_minor(), vec![], String::new());
+
+        // gap in the middle of logs fails the whole apply_all
+        let logs = [log1.clone(), log3.clone()];
+        let err = initial.apply_all(&logs, None).unwrap_err();
+        assert_eq!(err, ChangeLogError::VersionGap(v1, log3.version));
+        // logs not newer than map are skipped, not a gap
+        let map = initial.apply_change(&log1, None).unwrap();
+        assert_eq!(map.apply_all(&[log1], None).unwrap(), None);
+
+        let log = ChangeLog::new(
+            v1.next_major(),
+            vec![change(1, TargetChangeOp::Remove)],
+            String::new(),
+        );
+        assert_eq!(
+            map.apply_change(&log, None).unwrap_err(),
+            ChangeLogError::UnknownTarget(uuid(1))
+        );
+        // invalid changelog is rejected by builder too
+        let err = map
+            .change_builder()
+            .add(uuid(0), TargetChangeOp::Remove)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, ChangeLogError::NotRemovable(0, "UpOut"));
+
+        let err: crate::Error = err.into();
+        assert!(matches!(
+            err,
+            crate::Error::InvalidChangeLog(ChangeLogError::NotRemovable(0, _))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "invalid changelog: target 0 can't be removed in state UpOut"
+        );
+    }
+
+    #[test]
+    fn test_report_capacity() {
+        const GIB: u64 = 1 << 30;
+        let v1 = ClusterMapVersion::new(0, 1);
+        let log = ChangeLog::new(v1, vec![create(0, 0)], String::new());
+        let map = ClusterMap::new_initial().apply_change(&log, None).unwrap();
+        let log = map
+            .report_capacity(0, TargetCapacity::new(100 * GIB, 0))
+            .unwrap();
+        assert_eq!(log.version, map.version.next_minor());
+        let map = map.apply_change(&log, None).unwrap();
+
+        // within the same usage step
+        assert!(map
+            .report_capacity(0, TargetCapacity::new(100 * GIB, 4 * GIB))
+            .is_none());
+        assert!(map
+            .report_capacity(0, TargetCapacity::new(100 * GIB, 5 * GIB))
+            .is_some());
+        assert!(map
+            .report_capacity(0, TargetCapacity::new(200 * GIB, 0))
+            .is_some());
+        assert!(map.report_capacity(1, TargetCapacity::default()).is_none());
+
+        let capacity = TargetCapacity::new(100 * GIB, 200 * GIB);
+        assert_eq!(capacity.usage_step(), TargetCapacity::USAGE_STEPS);
+        assert_eq!(TargetCapacity::default().usage_step(), 0);
+    }
+
+    #[test]
+    fn test_drain_and_maintenance() {
+        let map = ClusterMap::new_initial();
+        let log = map
+            .change_builder()
+            .add(uuid(0), create(0, 0).op)
+            .add(uuid(1), create(1, 1).op)
+            .add(uuid(0), TargetChangeOp::Up(url(0)))
+            .add(uuid(0), TargetChangeOp::In)
+            .build()
+            .unwrap();
+        let map = map.apply_change(&log, None).unwrap();
+
+        // only IN targets can be drained
+        assert!(map.drain_target(1).is_none());
+        assert!(map.drain_target(2).is_none());
+        let log = map.drain_target(0).unwrap();
+        assert_eq!(log.version, map.version.next_major());
+        let map = map.apply_change(&log, None).unwrap();
+        let target = map.get_target(0).unwrap();
+        assert!(target.is_draining() && !target.is_in());
+        assert!(map.drain_target(0).is_none());
+
+        let host0 = map.get_target(0).unwrap().location.format_path();
+        let host0 = host0.rsplit_once('/').unwrap().0;
+        let log = map.set_maintenance(host0, true).unwrap();
+        assert_eq!(log.version, map.version.next_minor());
+        assert_eq!(log.changes.len(), 1);
+        let map = map.apply_change(&log, None).unwrap();
+        assert!(!map.get_target(0).unwrap().allow_auto_down());
+        assert!(map.get_target(1).unwrap().allow_auto_down());
+        assert!(map.set_maintenance(host0, true).is_none());
+        // prefix of a name is not a parent path
+        assert!(map
+            .set_maintenance(&host0[..host0.len() - 1], true)
+            .is_none());
+
+        let log = map.set_maintenance("", false).unwrap();
+        let map = map.apply_change(&log, None).unwrap();
+        assert!(map.targets.values().all(|target| target.allow_auto_down()));
+    }
+
+    #[test]
+    fn test_remove_target() {
+        let map = ClusterMap::new_initial();
+        // 0: DownOut, 1: UpOut, 2: DownIn, 3: UpDrain
+        let log = map
+            .change_builder()
+            .add(uuid(0), create(0, 0).op)
+            .add(uuid(1), create(1, 1).op)
+            .add(uuid(1), TargetChangeOp::Up(url(1)))
+            .add(uuid(2), create(2, 2).op)
+            .add(uuid(2), TargetChangeOp::In)
+            .add(uuid(3), create(3, 3).op)
+            .add(uuid(3), TargetChangeOp::Up(url(3)))
+            .build()
+            .unwrap();
+        let map = map.apply_change(&log, None).unwrap();
+        let log = map
+            .change_builder()
+            .add(uuid(3), TargetChangeOp::In)
+            .build()
+            .unwrap();
+        let map = map.apply_change(&log, None).unwrap();
+        let log = map.drain_target(3).unwrap();
+        let map = map.apply_change(&log, None).unwrap();
+
+        for (id, state) in [(1, "UpOut"), (2, "DownIn"), (3, "UpDrain")] {
+            assert!(map.remove_target(id).is_none());
+            let res = map
+                .change_builder()
+                .add(uuid(id), TargetChangeOp::Remove)
+                .build();
+            assert_eq!(res.unwrap_err(), ChangeLogError::NotRemovable(id, state));
+        }
+        assert!(map.remove_target(4).is_none());
+
+        let log = map.remove_target(0).unwrap();
+        assert_eq!(log.version, map.version.next_major());
+        let map = map.apply_change(&log, None).unwrap();
+        assert!(map.get_target(0).is_none());
+        assert!(map.get_target_by_uuid(&uuid(0)).is_none());
+        assert_eq!(map.targets.len(), 3);
+    }
 }
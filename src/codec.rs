@@ -0,0 +1,227 @@
+use crate::{ChangeLog, ClusterMap, ClusterMapVersion, Revision, TargetId, TargetInfo};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Current binary format version.
+///
+/// Bump it when the encoding of any `Codec` type changes, and decode the
+/// older format in `Codec::decode_legacy` of that type.
+///
+/// - 1: bincode with fixed size integers, `ClusterMap::revision` is an etcd revision.
+/// - 2: bincode with varint integers, `ClusterMap::revision` is a raft `Revision`.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Header: kind and format version.
+const HEADER_LEN: usize = 2;
+/// Trailer: CRC32 of header and payload, little endian.
+const CHECKSUM_LEN: usize = 4;
+
+/// Error of decoding.
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    /// Buffer is too short, `(len)`
+    #[error("buffer too short: {0} bytes")]
+    TooShort(usize),
+    /// Checksum mismatch, `(expected, actual)`
+    #[error("checksum mismatch, expected {0:#x}, actual {1:#x}")]
+    ChecksumMismatch(u32, u32),
+    /// Bytes encode another type, `(expected kind, actual kind)`
+    #[error("kind mismatch, expected {0}, actual {1}")]
+    KindMismatch(u8, u8),
+    /// Format version is not supported, `(format)`
+    #[error("unsupported format version {0}")]
+    UnsupportedFormat(u8),
+    /// Payload is malformed
+    #[error("malformed payload: {0}")]
+    Payload(#[from] bincode::Error),
+}
+
+/// Compact binary encoding for persisting and transmitting.
+///
+/// Layout: `kind: u8 | format: u8 | payload | crc32: u32`.
+/// Payload is bincode of the value in format `FORMAT_VERSION`.
+pub trait Codec: Serialize + DeserializeOwned {
+    /// Kind byte, unique for each type.
+    const KIND: u8;
+
+    /// Decode payload in an older format.
+    ///
+    /// Format 1 only differs in integer encoding, types whose fields
+    /// changed since then override this.
+    fn decode_legacy(format: u8, payload: &[u8]) -> Result<Self, CodecError> {
+        match format {
+            1 => Ok(bincode::deserialize(payload)?),
+            format => Err(CodecError::UnsupportedFormat(format)),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![Self::KIND, FORMAT_VERSION];
+        options()
+            .serialize_into(&mut buf, self)
+            .expect("serialize to vec never fails");
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, CodecError> {
+        if buf.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(CodecError::TooShort(buf.len()));
+        }
+        let (data, checksum) = buf.split_at(buf.len() - CHECKSUM_LEN);
+        let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+        let actual = crc32fast::hash(data);
+        if expected != actual {
+            return Err(CodecError::ChecksumMismatch(expected, actual));
+        }
+        let (kind, format, payload) = (data[0], data[1], &data[HEADER_LEN..]);
+        if kind != Self::KIND {
+            return Err(CodecError::KindMismatch(Self::KIND, kind));
+        }
+        match format {
+            FORMAT_VERSION => Ok(options().deserialize(payload)?),
+            format if format < FORMAT_VERSION => Self::decode_legacy(format, payload),
+            format => Err(CodecError::UnsupportedFormat(format)),
+        }
+    }
+}
+
+/// Bincode options of `FORMAT_VERSION`, varint integers.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl Codec for ClusterMap {
+    const KIND: u8 = 1;
+
+    /// Etcd revision of format 1 is meaningless for raft, it is reset to default.
+    fn decode_legacy(format: u8, payload: &[u8]) -> Result<Self, CodecError> {
+        #[derive(Deserialize)]
+        struct ClusterMapV1 {
+            version: ClusterMapVersion,
+            uuid_map: BTreeMap<Uuid, TargetId>,
+            targets: BTreeMap<TargetId, TargetInfo>,
+            #[allow(dead_code)]
+            revision: i64,
+        }
+
+        match format {
+            1 => {
+                let map: ClusterMapV1 = bincode::deserialize(payload)?;
+                Ok(ClusterMap {
+                    version: map.version,
+                    uuid_map: map.uuid_map,
+                    targets: map.targets,
+                    revision: Revision::default(),
+                })
+            }
+            format => Err(CodecError::UnsupportedFormat(format)),
+        }
+    }
+}
+
+impl Codec for ChangeLog {
+    const KIND: u8 = 2;
+}
+
+impl Codec for TargetInfo {
+    const KIND: u8 = 3;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TargetCapacity, TargetChangeOp, TargetLocation, TargetState};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_codec() {
+        let map = ClusterMap::new_initial();
+        let uuid = Uuid::new_v4();
+        let location = TargetLocation::new("r", "z", "rack", "host", "dev").unwrap();
+        let capacity = TargetCapacity::new(1 << 40, 1 << 30);
+        let log = map
+            .change_builder()
+            .add(uuid, TargetChangeOp::Create(0, None, location, capacity))
+            .add(uuid, TargetChangeOp::Up("10.0.0.1:8000".parse().unwrap()))
+            .info("create".to_owned())
+            .build()
+            .unwrap();
+        let map = map.apply_change(&log, Some(Revision::new(3, 1))).unwrap();
+
+        assert_eq!(ChangeLog::decode(&log.encode()).unwrap(), log);
+        assert_eq!(ClusterMap::decode(&map.encode()).unwrap(), map);
+        let target = map.get_target(0).unwrap();
+        assert_eq!(&TargetInfo::decode(&target.encode()).unwrap(), target);
+
+        let mut buf = map.encode();
+        assert!(matches!(
+            ChangeLog::decode(&buf),
+            Err(CodecError::KindMismatch(2, 1))
+        ));
+        buf[4] ^= 1;
+        assert!(matches!(
+            ClusterMap::decode(&buf),
+            Err(CodecError::ChecksumMismatch(_, _))
+        ));
+        assert!(matches!(
+            ClusterMap::decode(&buf[..3]),
+            Err(CodecError::TooShort(3))
+        ));
+
+        for format in [0, FORMAT_VERSION + 1] {
+            let mut buf = vec![ClusterMap::KIND, format];
+            buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+            assert!(matches!(
+                ClusterMap::decode(&buf),
+                Err(CodecError::UnsupportedFormat(f)) if f == format
+            ));
+        }
+    }
+
+    /// Map with target 3 UpOut at revision 7, encoded in format 1.
+    const MAP_V1: &[u8] = &[
+        1, 1, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 66, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 16, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 66, 3, 0, 0, 0, 3, 0, 0, 0,
+        0, 0, 0, 0, 10, 0, 0, 1, 64, 31, 1, 0, 0, 0, 0, 0, 0, 0, 114, 1, 0, 0, 0, 0, 0, 0, 0, 122,
+        4, 0, 0, 0, 0, 0, 0, 0, 114, 97, 99, 107, 4, 0, 0, 0, 0, 0, 0, 0, 104, 111, 115, 116, 3, 0,
+        0, 0, 0, 0, 0, 0, 100, 101, 118, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 0, 4, 0,
+        0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 112, 189, 193, 255,
+    ];
+
+    #[test]
+    fn test_codec_legacy() {
+        let location = TargetLocation::new("r", "z", "rack", "host", "dev").unwrap();
+        let capacity = TargetCapacity::new(1 << 40, 1 << 30);
+        let target = TargetInfo::new(Uuid::new_v4(), 7, None, true, location, capacity);
+
+        // format 1 encodes integers in fixed size
+        let mut legacy = vec![TargetInfo::KIND, 1];
+        bincode::serialize_into(&mut legacy, &target).unwrap();
+        legacy.extend_from_slice(&crc32fast::hash(&legacy).to_le_bytes());
+        assert_eq!(TargetInfo::decode(&legacy).unwrap(), target);
+
+        let buf = target.encode();
+        assert_eq!(buf[1], FORMAT_VERSION);
+        assert!(buf.len() < legacy.len());
+        assert_eq!(TargetInfo::decode(&buf).unwrap(), target);
+
+        let map = ClusterMap::decode(MAP_V1).unwrap();
+        assert_eq!(map.version, ClusterMapVersion::new(0, 1));
+        assert_eq!(map.revision, Revision::default());
+        let target = map.get_target(3).unwrap();
+        assert_eq!(map.uuid_map[&target.uuid], 3);
+        assert_eq!(
+            target.state,
+            TargetState::UpOut(3, "10.0.0.1:8000".parse().unwrap())
+        );
+        assert_eq!(target.location.format_path(), "/r/z/rack/host/dev");
+        assert_eq!(target.capacity, TargetCapacity::new(1 << 40, 1 << 30));
+        // re-encoded in current format
+        assert_eq!(ClusterMap::decode(&map.encode()).unwrap(), map);
+    }
+}
@@ -1,8 +1,8 @@
 use std::sync::{RwLock, Arc};
 use async_trait::async_trait;
 use tokio::sync::Mutex;
-use crate::{storage_mod::{Peer, KvEngine, RaftEngine}, ClusterMap, ClusterMapVersion, Error, Conf};
-use super::Rconf;
+use crate::{storage_mod::{Peer, KvEngine, RaftEngine}, ClusterMap, TargetChangeOp, ClusterMapVersion, Error, Conf, TargetId};
+use super::{Rconf, MapSubscriber, Replica};
 use crate::ClientCtl;
 
 
@@ -15,7 +15,11 @@ where
     raft_peer: Peer<EK, ER>,
     curr_map: RwLock<Arc<ClusterMap>>,
     update_lock: Mutex<()>,
-    conf: Rconf,
+    conf: Rconf<EK>,
+    /// Local replica of controller state, map changes are proposed through it.
+    replica: Arc<Replica<EK>>,
+    /// Receive map deltas pushed by controller nodes.
+    subscriber: MapSubscriber,
 
 }
 
@@ -27,20 +31,30 @@ where
 {
     /// Get current cluster map.
     fn current_map(&self) -> Arc<ClusterMap>{
-        todo!();
+        self.curr_map.read().unwrap().clone()
     }
 
     /// Update cluster map.
+    ///
+    /// Poll `subscriber` until map reaches `version_hit`.
     async fn update_map(
         &self,
         version_hit: Option<ClusterMapVersion>,
     ) -> Result<Arc<ClusterMap>, Error>{
-        todo!();
+        let _guard = self.update_lock.lock().await;
+        let mut map = self.current_map();
+        loop {
+            map = self.subscriber.poll(&map).await?;
+            *self.curr_map.write().unwrap() = map.clone();
+            if !matches!(version_hit, Some(version) if map.version < version) {
+                return Ok(map);
+            }
+        }
     }
 
     /// Get conf client.
     fn get_conf(&self) -> &dyn Conf{
-        todo!();
+        &self.conf
     }
 
     /// Add all targets IN.
@@ -49,6 +63,52 @@ where
     async fn add_all_targets(&self, cnt_hint: Option<u32>) -> Result<Arc<ClusterMap>, Error>{
         todo!();
     }
+
+    /// Set placement weight of target `id`.
+    async fn set_target_weight(&self, id: TargetId, weight: u32) -> Result<Arc<ClusterMap>, Error>{
+        self.replica.change_map(|map| {
+            map.get_target(id).ok_or(Error::InvalidArg)?;
+            Ok(map.set_weight(id, weight))
+        }).await
+    }
+
+    /// Mark target `id` DRAIN.
+    ///
+    /// Return `Error::InvalidArg` if target doesn't exist or is neither IN nor DRAIN.
+    async fn drain_target(&self, id: TargetId) -> Result<Arc<ClusterMap>, Error>{
+        self.replica.change_map(|map| {
+            let target = map.get_target(id).ok_or(Error::InvalidArg)?;
+            if target.is_draining() {
+                return Ok(None);
+            }
+            map.drain_target(id).map(Some).ok_or(Error::InvalidArg)
+        }).await
+    }
+
+    /// Enter or leave maintenance mode for targets under `path`.
+    async fn set_maintenance(&self, path: &str, maintenance: bool) -> Result<Arc<ClusterMap>, Error>{
+        self.replica.change_map(|map| Ok(map.set_maintenance(path, maintenance))).await
+    }
+
+    /// Remove DownOut target `id` from cluster map.
+    ///
+    /// Return `Error::InvalidArg` if target doesn't exist, `ChangeLogError::NotRemovable`
+    /// if it is not DownOut.
+    async fn remove_target(&self, id: TargetId) -> Result<Arc<ClusterMap>, Error>{
+        self.replica.change_map(|map| {
+            let target = map.get_target(id).ok_or(Error::InvalidArg)?;
+            let info = format!("remove target {}", id);
+            let log = map.change_builder().add(target.uuid, TargetChangeOp::Remove).info(info).build()?;
+            Ok(Some(log))
+        }).await
+    }
+
+    /// Get cluster map of `version` from controller history.
+    ///
+    /// Read from history of local replica, maps it hasn't applied yet are not found.
+    async fn map_at(&self, version: ClusterMapVersion) -> Result<Option<Arc<ClusterMap>>, Error>{
+        Ok(self.replica.map_at(version))
+    }
 }
 
 
@@ -1,45 +1,436 @@
-use std::sync::RwLock;
-use crate::{Conf, Error};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::{storage_mod::KvEngine, Compare, Conf, Error, KvPage, KvWatcher, LeaseId, ObjectClass, OcKind, OcType, TxnOp, TxnResponse};
 use async_trait::async_trait;
+use super::{Command, CommandResult, Replica};
 
-pub struct Rconf{
-    // (stripe_size, revision)
-    stripes: RwLock<Vec<Option<u32>>>,
-    // (chunk_size, revision)
-    chunks: RwLock<Vec<Option<u32>>>,
+pub struct Rconf<EK: KvEngine>{
+    // kv and chunk/stripe types are replicated by raft, read from local state machine,
+    // watches are served by its watch hub
+    replica: Arc<Replica<EK>>,
+}
+
+impl<EK: KvEngine> Rconf<EK>{
+    /// Conf on local `replica`, writes are proposed through it and wait until applied.
+    pub fn new(replica: Arc<Replica<EK>>) -> Rconf<EK>{
+        Rconf{ replica }
+    }
+
+    /// Propose `Command::OcCreate`.
+    async fn oc_create(&self, kind: OcKind, size: u32) -> Result<u8, Error>{
+        match self.replica.propose(Command::OcCreate(kind, size)).await? {
+            CommandResult::OcCreated(id) => Ok(id),
+            res => unreachable!("OcCreate returns OcCreated, got {:?}", res),
+        }
+    }
+
+    /// Propose `cmd` changing chunk or stripe types.
+    async fn oc_change(&self, cmd: Command) -> Result<(), Error>{
+        match self.replica.propose(cmd).await? {
+            CommandResult::OcChanged => Ok(()),
+            res => unreachable!("Oc commands return OcChanged, got {:?}", res),
+        }
+    }
 }
 
 #[async_trait]
-impl Conf for Rconf{
+impl<EK: KvEngine> Conf for Rconf<EK>{
     async fn oc_create_chunk_type(&self, chunk_size: u32) -> Result<u8, Error>{
-        todo!();
+        self.oc_create(OcKind::Chunk, chunk_size).await
     }
 
     async fn oc_create_stripe_type(&self, stripe_cnt: u32) -> Result<u8, Error>{
-        todo!();
+        self.oc_create(OcKind::Stripe, stripe_cnt).await
     }
 
     async fn oc_get(&self, stripe_type: u8, chunk_size: u8) -> Result<Option<(u32, u32)>, Error>{
-        todo!();
+        let sm = self.replica.state_machine();
+        let stripe = sm.oc_table(OcKind::Stripe).get_active(stripe_type)?;
+        let chunk = sm.oc_table(OcKind::Chunk).get_active(chunk_size)?;
+        Ok(stripe.zip(chunk))
+    }
+
+    /// Propose `Command::OcAcquire`.
+    async fn oc_acquire(&self, stripe_type: u8, chunk_type: u8) -> Result<(u32, u32), Error>{
+        match self.replica.propose(Command::OcAcquire(stripe_type, chunk_type)).await? {
+            CommandResult::OcAcquired(stripe_cnt, chunk_size) => Ok((stripe_cnt, chunk_size)),
+            res => unreachable!("OcAcquire returns OcAcquired, got {:?}", res),
+        }
+    }
+
+    /// Propose `Command::OcRelease`.
+    async fn oc_release(&self, stripe_type: u8, chunk_type: u8) -> Result<(), Error>{
+        self.oc_change(Command::OcRelease(stripe_type, chunk_type)).await
+    }
+
+    async fn oc_get_stripe(&self, stripe_type: u8) -> Result<Option<OcType>, Error>{
+        Ok(self.replica.state_machine().oc_table(OcKind::Stripe).get(stripe_type))
+    }
+
+    async fn oc_get_chunk(&self, chunk_type: u8) -> Result<Option<OcType>, Error>{
+        Ok(self.replica.state_machine().oc_table(OcKind::Chunk).get(chunk_type))
+    }
+
+    async fn oc_deprecate_chunk_type(&self, chunk_type: u8) -> Result<(), Error>{
+        self.oc_change(Command::OcDeprecate(OcKind::Chunk, chunk_type)).await
+    }
+
+    async fn oc_deprecate_stripe_type(&self, stripe_type: u8) -> Result<(), Error>{
+        self.oc_change(Command::OcDeprecate(OcKind::Stripe, stripe_type)).await
+    }
+
+    async fn oc_delete_chunk_type(&self, chunk_type: u8) -> Result<(), Error>{
+        self.oc_change(Command::OcDelete(OcKind::Chunk, chunk_type)).await
+    }
+
+    async fn oc_delete_stripe_type(&self, stripe_type: u8) -> Result<(), Error>{
+        self.oc_change(Command::OcDelete(OcKind::Stripe, stripe_type)).await
+    }
+
+    /// Propose `Command::OcCreateClass`, it is validated against cluster map of state machine.
+    async fn oc_create_class(&self, class: &ObjectClass) -> Result<(), Error>{
+        self.oc_change(Command::OcCreateClass(class.clone())).await
+    }
+
+    async fn oc_get_class(&self, name: &str) -> Result<Option<ObjectClass>, Error>{
+        Ok(self.replica.state_machine().oc_class(name))
+    }
+
+    async fn kv_get_bytes(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error>{
+        Ok(self.replica.state_machine().kv_get(key))
+    }
+
+    /// Propose `Command::KvPut`.
+    async fn kv_put_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64, Error>{
+        match self.replica.propose(Command::KvPut(key.to_vec(), value.to_vec())).await? {
+            CommandResult::Modified(revision) => Ok(revision),
+            res => unreachable!("KvPut returns Modified, got {:?}", res),
+        }
+    }
+
+    /// Propose `Command::KvDelete`.
+    async fn kv_delete_bytes(&self, key: &[u8]) -> Result<bool, Error>{
+        match self.replica.propose(Command::KvDelete(key.to_vec())).await? {
+            CommandResult::Deleted(existed) => Ok(existed),
+            res => unreachable!("KvDelete returns Deleted, got {:?}", res),
+        }
+    }
+
+    /// Propose `Command::KvCas`.
+    async fn kv_cas_bytes(&self, key: &[u8], expected_revision: u64, value: &[u8]) -> Result<u64, Error>{
+        let cmd = Command::KvCas(key.to_vec(), expected_revision, value.to_vec());
+        match self.replica.propose(cmd).await? {
+            CommandResult::Modified(revision) => Ok(revision),
+            res => unreachable!("KvCas returns Modified, got {:?}", res),
+        }
+    }
+
+    /// Propose `Command::Txn`.
+    async fn txn(
+        &self,
+        compares: Vec<Compare>,
+        success: Vec<TxnOp>,
+        failure: Vec<TxnOp>,
+    ) -> Result<TxnResponse, Error>{
+        match self.replica.propose(Command::Txn(compares, success, failure)).await? {
+            CommandResult::Txn(res) => Ok(res),
+            res => unreachable!("Txn returns Txn, got {:?}", res),
+        }
+    }
+
+    async fn kv_watch_bytes(&self, prefix: &[u8], from_revision: u64) -> Result<KvWatcher, Error>{
+        self.replica.watch_hub().watch(prefix, from_revision)
     }
 
-    async fn oc_get_stripe(&self, stripe_type: u8) -> Result<Option<u32>, Error>{
-        todo!();
+    async fn kv_scan_bytes(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<(Vec<u8>, Vec<u8>), Vec<u8>>, Error>{
+        let page = self.replica.state_machine().kv_scan(prefix, start, limit)?;
+        let items = page.items.into_iter().map(|(key, value, _)| (key, value)).collect();
+        Ok(KvPage{ items, next: page.next })
     }
 
-    async fn oc_get_chunk(&self, chunk_type: u8) -> Result<Option<u32>, Error>{
-        todo!();
+    async fn kv_scan_keys_bytes(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<Vec<u8>, Vec<u8>>, Error>{
+        self.replica.state_machine().kv_scan_keys(prefix, start, limit)
     }
 
-    async fn kv_get(&self, key: &str) -> Result<Option<String>, Error>{
-        todo!();
+    async fn kv_count_bytes(&self, prefix: &[u8]) -> Result<u64, Error>{
+        self.replica.state_machine().kv_count(prefix)
     }
 
-    async fn kv_put(&self, key: &str, value: &str) -> Result<(), Error>{
-        todo!();
+    /// Propose `Command::LeaseGrant`.
+    async fn lease_grant(&self, ttl: Duration) -> Result<LeaseId, Error>{
+        match self.replica.propose(Command::LeaseGrant(ttl)).await? {
+            CommandResult::Granted(id) => Ok(id),
+            res => unreachable!("LeaseGrant returns Granted, got {:?}", res),
+        }
     }
 
-    async fn kv_get_all(&self, prefix: &str) -> Result<Vec<(String, String)>, Error>{
-        todo!();
+    /// Record keep-alive in lease tracker of replica, it must be leader.
+    async fn lease_keep_alive(&self, id: LeaseId) -> Result<(), Error>{
+        self.replica.lease_keep_alive(id)
+    }
+
+    /// Propose `Command::LeaseRevoke`.
+    async fn lease_revoke(&self, id: LeaseId) -> Result<usize, Error>{
+        match self.replica.propose(Command::LeaseRevoke(id)).await? {
+            CommandResult::Revoked(cnt) => Ok(cnt),
+            res => unreachable!("LeaseRevoke returns Revoked, got {:?}", res),
+        }
+    }
+
+    /// Propose `Command::KvPutLease`.
+    async fn kv_put_lease_bytes(&self, key: &[u8], value: &[u8], id: LeaseId) -> Result<u64, Error>{
+        match self.replica.propose(Command::KvPutLease(key.to_vec(), value.to_vec(), id)).await? {
+            CommandResult::Modified(revision) => Ok(revision),
+            res => unreachable!("KvPutLease returns Modified, got {:?}", res),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::storage_mod::BasicEngine;
+    use crate::ctl::replica::tests::local_replica;
+    use crate::{FailureDomain, KvEvent, Redundancy, TargetChangeOp, TargetLocation, TxnOpResult, KV_GET_ALL_LIMIT, MAX_OC_TYPES};
+    use uuid::Uuid;
+    use tempfile::TempDir;
+
+    /// Conf on a single node replica.
+    pub(crate) fn local_conf() -> (Rconf<BasicEngine>, TempDir) {
+        let (replica, path) = local_replica();
+        (Rconf::new(replica), path)
+    }
+
+    #[madsim::test]
+    async fn test_kv_write() {
+        let (conf, _path) = local_conf();
+        let rev1 = conf.kv_put_bytes(b"a", b"1").await.unwrap();
+        assert_eq!(conf.kv_get_bytes(b"a").await.unwrap(), Some((b"1".to_vec(), rev1)));
+
+        assert!(matches!(conf.kv_cas_bytes(b"a", 0, b"2").await, Err(Error::TxnConflict)));
+        assert!(matches!(conf.kv_cas_bytes(b"a", rev1 + 1, b"2").await, Err(Error::TxnConflict)));
+        let rev2 = conf.kv_cas_bytes(b"a", rev1, b"2").await.unwrap();
+        assert!(rev2 > rev1);
+        assert_eq!(conf.kv_get_bytes(b"a").await.unwrap(), Some((b"2".to_vec(), rev2)));
+        // 0 creates a key that doesn't exist
+        let rev3 = conf.kv_cas_bytes(b"b", 0, b"3").await.unwrap();
+        assert_eq!(conf.kv_get_bytes(b"b").await.unwrap(), Some((b"3".to_vec(), rev3)));
+
+        assert!(conf.kv_delete_bytes(b"a").await.unwrap());
+        assert!(!conf.kv_delete_bytes(b"a").await.unwrap());
+        assert_eq!(conf.kv_get_bytes(b"a").await.unwrap(), None);
+        assert_eq!(conf.kv_count_bytes(b"").await.unwrap(), 1);
+    }
+
+    #[madsim::test]
+    async fn test_kv_watch() {
+        let (conf, _path) = local_conf();
+        let mut watcher = conf.kv_watch_bytes(b"a/", 0).await.unwrap();
+        let rev1 = conf.kv_put_bytes(b"a/1", b"1").await.unwrap();
+        conf.kv_put_bytes(b"b/1", b"1").await.unwrap();
+        let rev2 = conf.kv_cas_bytes(b"a/1", rev1, b"2").await.unwrap();
+        // failed command publishes nothing
+        assert!(conf.kv_cas_bytes(b"a/1", rev1, b"3").await.is_err());
+        conf.kv_delete_bytes(b"a/1").await.unwrap();
+        let rev3 = conf.kv_put_bytes(b"a/2", b"1").await.unwrap();
+
+        let put = |value: &[u8], revision| KvEvent::Put(b"a/1".to_vec(), value.to_vec(), revision);
+        assert_eq!(watcher.next().await, Some(put(b"1", rev1)));
+        assert_eq!(watcher.next().await, Some(put(b"2", rev2)));
+        let delete = watcher.next().await.unwrap();
+        assert!(matches!(delete, KvEvent::Delete(ref key, _) if key == b"a/1"));
+        assert_eq!(watcher.next().await.unwrap().revision(), rev3);
+
+        // resume after the delete
+        let mut resumed = conf.kv_watch_bytes(b"a/", delete.revision() + 1).await.unwrap();
+        assert_eq!(resumed.next().await.unwrap().revision(), rev3);
+    }
+
+    #[madsim::test]
+    async fn test_txn() {
+        let (conf, _path) = local_conf();
+        let key = b"a".to_vec();
+        let create = || {
+            let compares = vec![Compare::RevisionEquals(key.clone(), 0)];
+            let success = vec![TxnOp::Put(key.clone(), b"1".to_vec()), TxnOp::Get(key.clone())];
+            (compares, success)
+        };
+        let (compares, success) = create();
+        let res = conf.txn(compares, success, vec![]).await.unwrap();
+        let revision = match res.results[..] {
+            [TxnOpResult::Put(revision), _] => revision,
+            _ => panic!("unexpected results {:?}", res.results),
+        };
+        assert!(res.succeeded);
+        assert_eq!(res.results[1], TxnOpResult::Get(Some((b"1".to_vec(), revision))));
+
+        // compares fail, without failure ops nothing is applied
+        let (compares, success) = create();
+        assert!(matches!(conf.txn(compares, success, vec![]).await, Err(Error::TxnConflict)));
+        let (compares, success) = create();
+        let res = conf.txn(compares, success, vec![TxnOp::Delete(key.clone())]).await.unwrap();
+        assert!(!res.succeeded);
+        assert_eq!(res.results, vec![TxnOpResult::Delete(true)]);
+        assert_eq!(conf.kv_get_bytes(&key).await.unwrap(), None);
+    }
+
+    #[madsim::test]
+    async fn test_kv_str() {
+        let (conf, _path) = local_conf();
+        let rev1 = conf.kv_put("vol/1", "a").await.unwrap();
+        assert_eq!(conf.kv_get("vol/1").await.unwrap(), Some(("a".to_owned(), rev1)));
+        let rev2 = conf.kv_cas("vol/1", rev1, "b").await.unwrap();
+        assert!(matches!(conf.kv_cas("vol/1", rev1, "c").await, Err(Error::TxnConflict)));
+        conf.kv_put("vol/2", "c").await.unwrap();
+        conf.kv_put("vom", "d").await.unwrap();
+
+        let pair = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+        let page = conf.kv_scan("vol/", "", 1).await.unwrap();
+        assert_eq!(page.items, vec![pair("vol/1", "b")]);
+        assert_eq!(page.next.as_deref(), Some("vol/2"));
+        let page = conf.kv_scan_keys("vol/", "vol/2", 10).await.unwrap();
+        assert_eq!(page.items, vec!["vol/2".to_owned()]);
+        assert_eq!(page.next, None);
+        assert_eq!(conf.kv_count("vol/").await.unwrap(), 2);
+        let all = conf.kv_get_all("vol/").await.unwrap();
+        assert_eq!(all, vec![pair("vol/1", "b"), pair("vol/2", "c")]);
+
+        let mut watcher = conf.kv_watch("vol/", rev2).await.unwrap();
+        assert!(matches!(watcher.next().await, Some(KvEvent::Put(key, _, _)) if key == b"vol/1"));
+        assert!(conf.kv_delete("vol/2").await.unwrap());
+        assert!(!conf.kv_delete("vol/2").await.unwrap());
+
+        // values and keys that are not UTF-8
+        conf.kv_put_bytes(b"vol/3", b"\xff").await.unwrap();
+        assert!(matches!(conf.kv_get("vol/3").await, Err(Error::InvalidArg)));
+        assert!(matches!(conf.kv_scan("vol/", "", 10).await, Err(Error::InvalidArg)));
+        assert!(matches!(conf.kv_get_all("vol/").await, Err(Error::InvalidArg)));
+        assert_eq!(conf.kv_scan_keys("vol/", "", 10).await.unwrap().items.len(), 2);
+        conf.kv_put_bytes(b"vol/\xff", b"e").await.unwrap();
+        assert!(matches!(conf.kv_scan_keys("vol/", "", 10).await, Err(Error::InvalidArg)));
+        assert_eq!(conf.kv_count("vol/").await.unwrap(), 3);
+
+        let puts = (0..=KV_GET_ALL_LIMIT)
+            .map(|i| TxnOp::Put(format!("many/{}", i).into_bytes(), Vec::new()))
+            .collect();
+        conf.txn(vec![], puts, vec![]).await.unwrap();
+        assert!(matches!(
+            conf.kv_get_all("many/").await,
+            Err(Error::TooManyKeys(KV_GET_ALL_LIMIT))
+        ));
+    }
+
+    #[madsim::test]
+    async fn test_lease_expiry() {
+        let (conf, _path) = local_conf();
+        let replica = conf.replica.clone();
+        let interval = Duration::from_millis(500);
+        madsim::task::spawn(async move { replica.run_leader_tick(interval).await }).detach();
+        let ttl = Duration::from_secs(3);
+        let id = conf.lease_grant(ttl).await.unwrap();
+        conf.kv_put_lease("mount/a", "client", id).await.unwrap();
+        conf.kv_put_lease("mount/b", "client", id).await.unwrap();
+
+        // kept alive well past its ttl
+        for _ in 0..5 {
+            madsim::time::sleep(Duration::from_secs(2)).await;
+            conf.lease_keep_alive(id).await.unwrap();
+        }
+        assert_eq!(conf.kv_count("mount/").await.unwrap(), 2);
+
+        // expired by leader once keep-alives stop
+        madsim::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(conf.kv_count("mount/").await.unwrap(), 2);
+        madsim::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(conf.kv_count("mount/").await.unwrap(), 0);
+        let put = conf.kv_put_lease("mount/a", "", id).await;
+        assert!(matches!(put, Err(Error::LeaseNotFound(_))));
+        assert!(matches!(conf.lease_keep_alive(id).await, Err(Error::LeaseNotFound(_))));
+
+        let id = conf.lease_grant(ttl).await.unwrap();
+        conf.kv_put_lease("mount/c", "client", id).await.unwrap();
+        // a put without lease detaches the key
+        conf.kv_put_lease("mount/d", "client", id).await.unwrap();
+        conf.kv_put("mount/d", "other").await.unwrap();
+        assert_eq!(conf.lease_revoke(id).await.unwrap(), 1);
+        assert!(matches!(conf.lease_revoke(id).await, Err(Error::LeaseNotFound(_))));
+        assert_eq!(conf.kv_get("mount/d").await.unwrap().unwrap().0, "other");
+    }
+
+    #[madsim::test]
+    async fn test_oc_types() {
+        let (conf, _path) = local_conf();
+        for i in 0..MAX_OC_TYPES {
+            assert_eq!(conf.oc_create_chunk_type(4096 + i as u32).await.unwrap(), i as u8);
+        }
+        assert!(matches!(conf.oc_create_chunk_type(1).await, Err(Error::MaxChunkType)));
+        assert_eq!(conf.oc_create_stripe_type(6).await.unwrap(), 0);
+        assert_eq!(conf.oc_get(0, 3).await.unwrap(), Some((6, 4099)));
+        assert_eq!(conf.oc_get(1, 3).await.unwrap(), None);
+
+        assert_eq!(conf.oc_acquire(0, 3).await.unwrap(), (6, 4099));
+        assert!(matches!(conf.oc_acquire(1, 3).await, Err(Error::OcTypeNotFound(1))));
+        conf.oc_deprecate_chunk_type(3).await.unwrap();
+        assert!(matches!(conf.oc_get(0, 3).await, Err(Error::OcTypeDeprecated(3))));
+        assert!(matches!(conf.oc_acquire(0, 3).await, Err(Error::OcTypeDeprecated(3))));
+        assert_eq!(conf.oc_get_chunk(3).await.unwrap().unwrap().refs, 1);
+
+        // id is not freed while the object uses it
+        assert!(matches!(conf.oc_delete_chunk_type(3).await, Err(Error::OcTypeInUse(3, 1))));
+        assert!(matches!(conf.oc_delete_stripe_type(0).await, Err(Error::OcTypeNotDeprecated(0))));
+        conf.oc_release(0, 3).await.unwrap();
+        conf.oc_delete_chunk_type(3).await.unwrap();
+        assert_eq!(conf.oc_get_chunk(3).await.unwrap(), None);
+        assert_eq!(conf.oc_create_chunk_type(1).await.unwrap(), 3);
+
+        conf.oc_deprecate_stripe_type(0).await.unwrap();
+        conf.oc_delete_stripe_type(0).await.unwrap();
+        assert_eq!(conf.oc_get_stripe(0).await.unwrap(), None);
+        assert!(matches!(conf.oc_deprecate_stripe_type(0).await, Err(Error::OcTypeNotFound(0))));
+    }
+
+    #[madsim::test]
+    async fn test_oc_class() {
+        let (conf, _path) = local_conf();
+        let mut class = ObjectClass {
+            name: "rep-3".to_owned(),
+            chunk_size: 1 << 20,
+            redundancy: Redundancy::Replication(3),
+            failure_domain: FailureDomain::Host,
+        };
+        // validated against map of controller, it has no targets yet
+        assert!(matches!(conf.oc_create_class(&class).await, Err(Error::NotEnoughDomains(3, 0))));
+        conf.replica.change_map(|map| {
+            let mut builder = map.change_builder();
+            for i in 0..3 {
+                let location = TargetLocation::with_host(&format!("host{}", i), "dev").unwrap();
+                let create = TargetChangeOp::Create(i, None, location, Default::default());
+                let uuid = Uuid::from_u128(i as u128);
+                builder = builder.add(uuid, create).add(uuid, TargetChangeOp::In)
+                    .add(uuid, TargetChangeOp::SetWeight(1));
+            }
+            Ok(Some(builder.build()?))
+        }).await.unwrap();
+
+        conf.oc_create_class(&class).await.unwrap();
+        assert_eq!(conf.oc_get_class("rep-3").await.unwrap(), Some(class.clone()));
+        assert_eq!(conf.oc_get_class("rep-4").await.unwrap(), None);
+        // not in user namespace
+        assert_eq!(conf.kv_scan_bytes(b"", b"", 10).await.unwrap().items, vec![]);
+        assert!(matches!(conf.oc_create_class(&class).await, Err(Error::OcClassExists)));
+        class.name = "rep-4".to_owned();
+        class.redundancy = Redundancy::Replication(4);
+        assert!(matches!(conf.oc_create_class(&class).await, Err(Error::NotEnoughDomains(4, 3))));
+        assert_eq!(conf.oc_get_class("rep-4").await.unwrap(), None);
     }
 }
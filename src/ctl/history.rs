@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use madsim::time::Instant;
+use crate::{cluster::ChangeLogError, ChangeLog, ClusterMap, ClusterMapVersion, Revision};
+
+/// How long cluster map history is retained.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    /// Max number of changelogs to keep.
+    pub max_logs: usize,
+    /// Max age of changelogs to keep.
+    pub max_age: Duration,
+    /// Take a full map checkpoint every `checkpoint_interval` changelogs.
+    pub checkpoint_interval: usize,
+}
+
+struct LogEntry {
+    time: Instant,
+    revision: Revision,
+    log: ChangeLog,
+}
+
+/// Retained cluster map history on controller.
+///
+/// History is kept as full map checkpoints plus changelogs after them,
+/// map of any retained version is rebuilt from the nearest checkpoint.
+/// History always starts at a checkpoint, so retention is rounded up to checkpoints.
+pub struct MapHistory {
+    policy: RetentionPolicy,
+    /// Sorted by version, never empty.
+    checkpoints: VecDeque<Arc<ClusterMap>>,
+    logs: VecDeque<LogEntry>,
+    latest: Arc<ClusterMap>,
+}
+
+impl MapHistory {
+    pub fn new(map: Arc<ClusterMap>, policy: RetentionPolicy) -> MapHistory {
+        MapHistory {
+            policy,
+            checkpoints: VecDeque::from(vec![map.clone()]),
+            logs: VecDeque::new(),
+            latest: map,
+        }
+    }
+
+    pub fn policy(&self) -> RetentionPolicy {
+        self.policy
+    }
+
+    /// Get latest cluster map.
+    pub fn latest(&self) -> Arc<ClusterMap> {
+        self.latest.clone()
+    }
+
+    /// Oldest version we can rebuild.
+    pub fn oldest_version(&self) -> ClusterMapVersion {
+        self.checkpoints[0].version
+    }
+
+    /// Record changelog committed at `revision`, return the new map.
+    pub fn append(
+        &mut self,
+        log: ChangeLog,
+        revision: Revision,
+        now: Instant,
+    ) -> Result<Arc<ClusterMap>, ChangeLogError> {
+        let map = Arc::new(self.latest.apply_change(&log, Some(revision))?);
+        self.logs.push_back(LogEntry {
+            time: now,
+            revision,
+            log,
+        });
+        let last_checkpoint = self.checkpoints.back().unwrap().version;
+        let since_checkpoint = self
+            .logs
+            .iter()
+            .rev()
+            .take_while(|entry| entry.log.version > last_checkpoint)
+            .count();
+        if since_checkpoint >= self.policy.checkpoint_interval {
+            self.checkpoints.push_back(map.clone());
+        }
+        self.latest = map.clone();
+        self.trim(now);
+        Ok(map)
+    }
+
+    /// Drop history out of retention policy.
+    pub fn trim(&mut self, now: Instant) {
+        let len = self.logs.len();
+        let expired = self
+            .logs
+            .iter()
+            .enumerate()
+            .take_while(|(i, entry)| {
+                len - i > self.policy.max_logs
+                    || now.saturating_duration_since(entry.time) > self.policy.max_age
+            })
+            .count();
+        if expired == 0 {
+            return;
+        }
+        let newest_expired = self.logs[expired - 1].log.version;
+        // newest checkpoint we can start from without expired changelogs
+        let keep = self
+            .checkpoints
+            .iter()
+            .rposition(|map| map.version <= newest_expired)
+            .unwrap_or(0);
+        self.checkpoints.drain(..keep);
+        let start = self.checkpoints[0].version;
+        while matches!(self.logs.front(), Some(entry) if entry.log.version <= start) {
+            self.logs.pop_front();
+        }
+    }
+
+    /// Rebuild cluster map of `version`, None if not retained or never exists.
+    pub fn map_at(&self, version: ClusterMapVersion) -> Option<Arc<ClusterMap>> {
+        if version == self.latest.version {
+            return Some(self.latest.clone());
+        }
+        let checkpoint = self.checkpoints.iter().rev().find(|map| map.version <= version)?;
+        let entries: Vec<&LogEntry> = self
+            .logs
+            .iter()
+            .skip_while(|entry| entry.log.version <= checkpoint.version)
+            .take_while(|entry| entry.log.version <= version)
+            .collect();
+        let last = match entries.last() {
+            Some(last) => last,
+            None if checkpoint.version == version => return Some(checkpoint.clone()),
+            None => return None,
+        };
+        if last.log.version != version {
+            return None;
+        }
+        let logs: Vec<ChangeLog> = entries.iter().map(|entry| entry.log.clone()).collect();
+        let map = checkpoint
+            .apply_all(&logs, Some(last.revision))
+            .expect("retained changelogs are validated")
+            .expect("retained changelogs are newer than checkpoint");
+        Some(Arc::new(map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TargetChangeOp, TargetLocation};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_map_history() {
+        let policy = RetentionPolicy {
+            max_logs: 5,
+            max_age: Duration::from_secs(100),
+            checkpoint_interval: 3,
+        };
+        let start = Instant::now();
+        let mut history = MapHistory::new(Arc::new(ClusterMap::new_initial()), policy);
+        let mut maps = vec![history.latest()];
+        let location = TargetLocation::with_host("host", "dev").unwrap();
+        for i in 0..10u32 {
+            let latest = history.latest();
+            let (uuid, op) = if i % 2 == 0 {
+                let create = TargetChangeOp::Create(i, None, location.clone(), Default::default());
+                (Uuid::from_u128(i as u128), create)
+            } else {
+                let url = format!("10.0.0.1:{}", 8000 + i).parse().unwrap();
+                (Uuid::from_u128(i as u128 - 1), TargetChangeOp::Up(url))
+            };
+            let log = latest.change_builder().add(uuid, op).build().unwrap();
+            let now = start + Duration::from_secs(i as u64);
+            maps.push(history.append(log, Revision::new(i as u64 + 1, 1), now).unwrap());
+        }
+
+        // last 5 logs retained, rounded to checkpoint of maps[3]
+        assert_eq!(history.oldest_version(), maps[3].version);
+        assert!(history.map_at(maps[2].version).is_none());
+        for map in &maps[3..] {
+            assert_eq!(&history.map_at(map.version).unwrap(), map);
+        }
+        assert!(history.map_at(ClusterMapVersion::new(42, 3)).is_none());
+
+        history.trim(start + Duration::from_secs(1000));
+        assert_eq!(history.oldest_version(), maps[9].version);
+        assert_eq!(&history.map_at(maps[10].version).unwrap(), &maps[10]);
+        assert!(history.map_at(maps[8].version).is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+use madsim::time::Instant;
+use crate::{ClusterMap, TargetId};
+
+/// Track when each key was last heard from on controller leader.
+///
+/// Shared by target heartbeats and lease keep-alives. Keys we haven't heard
+/// from since we became leader count from then, so they get a full timeout.
+pub struct DeadlineTracker<K> {
+    /// Time we became leader.
+    start: Instant,
+    last_seen: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq + Copy> DeadlineTracker<K> {
+    pub fn new(now: Instant) -> DeadlineTracker<K> {
+        DeadlineTracker {
+            start: now,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Record `key` is alive at `now`.
+    pub fn touch(&mut self, key: K, now: Instant) {
+        self.last_seen.insert(key, now);
+    }
+
+    /// Forget `key`, eg: target removed or lease revoked.
+    pub fn forget(&mut self, key: K) {
+        self.last_seen.remove(&key);
+    }
+
+    /// How long `key` has been silent at `now`.
+    pub fn silent_for(&self, key: K, now: Instant) -> Duration {
+        let last = self.last_seen.get(&key).unwrap_or(&self.start);
+        now.saturating_duration_since(*last)
+    }
+
+    /// Keys in `keys` silent for longer than their timeout.
+    pub fn expired<I>(&self, keys: I, now: Instant) -> Vec<K>
+    where
+        I: IntoIterator<Item = (K, Duration)>,
+    {
+        keys.into_iter()
+            .filter(|&(key, timeout)| self.silent_for(key, now) > timeout)
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+/// Heartbeat timeouts of targets.
+#[derive(Debug, Clone, Copy)]
+pub struct LivenessPolicy {
+    /// Mark UP target DOWN if no heartbeat in it.
+    pub down_timeout: Duration,
+    /// Mark DOWN target OUT if no heartbeat in it.
+    pub out_timeout: Duration,
+}
+
+/// Track heartbeats of targets on controller leader.
+///
+/// Leader marks targets DOWN if no heartbeat in `down_timeout`,
+/// and DOWN targets OUT if no heartbeat in `out_timeout`.
+/// Targets in maintenance are never marked automatically.
+pub struct LivenessTracker {
+    policy: LivenessPolicy,
+    heartbeats: DeadlineTracker<TargetId>,
+}
+
+impl LivenessTracker {
+    pub fn new(policy: LivenessPolicy, now: Instant) -> LivenessTracker {
+        LivenessTracker {
+            policy,
+            heartbeats: DeadlineTracker::new(now),
+        }
+    }
+
+    /// Record heartbeat of target `id`.
+    pub fn heartbeat(&mut self, id: TargetId, now: Instant) {
+        self.heartbeats.touch(id, now);
+    }
+
+    /// Forget target `id`, eg: after it is removed from cluster map.
+    pub fn forget(&mut self, id: TargetId) {
+        self.heartbeats.forget(id);
+    }
+
+    /// UP targets that should be marked DOWN.
+    pub fn expired_up(&self, map: &ClusterMap, now: Instant) -> Vec<TargetId> {
+        let up = map
+            .targets
+            .iter()
+            .filter(|(_, target)| target.is_up() && target.allow_auto_down())
+            .map(|(&id, _)| (id, self.policy.down_timeout));
+        self.heartbeats.expired(up, now)
+    }
+
+    /// DOWN targets that still hold data and should be marked OUT.
+    pub fn expired_in(&self, map: &ClusterMap, now: Instant) -> Vec<TargetId> {
+        let down = map
+            .targets
+            .iter()
+            .filter(|(_, target)| !target.is_up() && (target.is_in() || target.is_draining()))
+            .filter(|(_, target)| target.allow_auto_down())
+            .map(|(&id, _)| (id, self.policy.out_timeout));
+        self.heartbeats.expired(down, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TargetChangeOp, TargetLocation};
+    use std::net::SocketAddr;
+    use uuid::Uuid;
+
+    /// Targets 0: UpIn, 1: UpIn in maintenance, 2: DownIn, 3: UpOut, 4: DownDrain.
+    fn build_map() -> ClusterMap {
+        use TargetChangeOp::*;
+        // ops of each target in two changelogs, after it is created UpOut
+        let targets: [(&[TargetChangeOp], &[TargetChangeOp]); 5] = [
+            (&[In], &[]),
+            (&[In, Maintenance(true)], &[]),
+            (&[In], &[Down]),
+            (&[], &[]),
+            (&[In], &[Drain, Down]),
+        ];
+        let uuid = |i: usize| Uuid::from_u128(i as u128 + 1);
+        let mut map = ClusterMap::new_initial();
+        let mut builder = map.change_builder();
+        for (i, (ops, _)) in targets.iter().enumerate() {
+            let url = SocketAddr::from(([10, 0, 0, i as u8], 8000));
+            let location = TargetLocation::with_host(&format!("host{}", i), "dev").unwrap();
+            let create = Create(i as TargetId, Some(url), location, Default::default());
+            builder = ops
+                .iter()
+                .fold(builder.add(uuid(i), create), |builder, op| {
+                    builder.add(uuid(i), op.clone())
+                });
+        }
+        let log = builder.build().unwrap();
+        map = map.apply_change(&log, None).unwrap();
+        let mut builder = map.change_builder();
+        for (i, (_, ops)) in targets.iter().enumerate() {
+            builder = ops
+                .iter()
+                .fold(builder, |builder, op| builder.add(uuid(i), op.clone()));
+        }
+        let log = builder.build().unwrap();
+        map.apply_change(&log, None).unwrap()
+    }
+
+    #[madsim::test]
+    async fn test_liveness_tracker() {
+        let map = build_map();
+        let secs = Duration::from_secs;
+        let start = Instant::now();
+        let policy = LivenessPolicy {
+            down_timeout: secs(1),
+            out_timeout: secs(10),
+        };
+        let mut tracker = LivenessTracker::new(policy, start);
+        tracker.heartbeat(0, start + secs(2));
+
+        // targets not heard from since we became leader count from `start`
+        assert!(tracker.expired_up(&map, start + secs(1)).is_empty());
+        assert_eq!(tracker.expired_up(&map, start + secs(2)), vec![3]);
+        // target in maintenance is never marked
+        assert_eq!(tracker.expired_up(&map, start + secs(4)), vec![0, 3]);
+
+        assert!(tracker.expired_in(&map, start + secs(10)).is_empty());
+        assert_eq!(tracker.expired_in(&map, start + secs(11)), vec![2, 4]);
+        tracker.heartbeat(2, start + secs(5));
+        assert_eq!(tracker.expired_in(&map, start + secs(11)), vec![4]);
+        tracker.forget(2);
+        assert_eq!(tracker.expired_in(&map, start + secs(11)), vec![2, 4]);
+    }
+}
@@ -0,0 +1,293 @@
+use std::time::Duration;
+use madsim::time::Instant;
+use crate::{Compare, Conf, Error, KvEvent, KvWatcher, LeaseId, TxnOp, TxnOpResult};
+
+/// Prefix of lock keys in `Conf` namespace.
+pub const LOCK_PREFIX: &str = "lock/";
+
+/// Distributed lock on `Conf`, also used for leader election.
+///
+/// Lock key is created only if absent and attached to a lease of the holder,
+/// so it is released when the holder dies and stops keeping the lease alive.
+/// Value of lock key is the holder, eg: address of the elected leader.
+pub struct ConfLock<'a> {
+    conf: &'a dyn Conf,
+    key: Vec<u8>,
+    ttl: Duration,
+}
+
+/// Held lock, released by `unlock` or when its lease expires.
+///
+/// Holder must call `ConfLock::keep_alive` every `ConfLock::keep_alive_interval`,
+/// the lock is lost once its lease expires. Resources guarded by the lock
+/// should check the fencing token, a holder may not notice it lost the lock.
+#[derive(Debug)]
+pub struct LockGuard {
+    key: Vec<u8>,
+    lease: LeaseId,
+    token: u64,
+}
+
+impl LockGuard {
+    /// Fencing token, mod revision of lock key.
+    ///
+    /// Tokens increase with every acquisition, so resources can reject
+    /// requests from stale holders by the token.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Lease of the lock.
+    pub fn lease(&self) -> LeaseId {
+        self.lease
+    }
+}
+
+impl<'a> ConfLock<'a> {
+    /// Lock `name`, holder should keep it alive within `ttl`.
+    pub fn new(conf: &'a dyn Conf, name: &str, ttl: Duration) -> ConfLock<'a> {
+        ConfLock {
+            conf,
+            key: format!("{}{}", LOCK_PREFIX, name).into_bytes(),
+            ttl,
+        }
+    }
+
+    /// Interval to keep lease alive, a third of ttl so a lost keep-alive is tolerated.
+    pub fn keep_alive_interval(&self) -> Duration {
+        self.ttl / 3
+    }
+
+    /// Current holder and fencing token, None if not locked.
+    pub async fn holder(&self) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        self.conf.kv_get_bytes(&self.key).await
+    }
+
+    /// Try to acquire lock as `holder`, None if it is held by others.
+    pub async fn try_lock(&self, holder: &[u8]) -> Result<Option<LockGuard>, Error> {
+        let lease = self.conf.lease_grant(self.ttl).await?;
+        match self.try_acquire(holder, lease).await {
+            Ok(Ok(guard)) => Ok(Some(guard)),
+            res => {
+                self.conf.lease_revoke(lease).await?;
+                res.map(|_| None)
+            }
+        }
+    }
+
+    /// Acquire lock as `holder`, wait until it is released by others.
+    ///
+    /// One lease is granted for all attempts and kept alive while waiting.
+    pub async fn lock(&self, holder: &[u8]) -> Result<LockGuard, Error> {
+        let lease = self.conf.lease_grant(self.ttl).await?;
+        let res = self.lock_with(holder, lease).await;
+        if res.is_err() {
+            let _ = self.conf.lease_revoke(lease).await;
+        }
+        res
+    }
+
+    /// Keep held lock alive, `Error::LeaseNotFound` if it is already lost.
+    pub async fn keep_alive(&self, guard: &LockGuard) -> Result<(), Error> {
+        self.conf.lease_keep_alive(guard.lease).await
+    }
+
+    /// Release lock and revoke its lease.
+    pub async fn unlock(&self, guard: LockGuard) -> Result<(), Error> {
+        debug_assert_eq!(guard.key, self.key);
+        match self.conf.lease_revoke(guard.lease).await {
+            Ok(_) | Err(Error::LeaseNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn lock_with(&self, holder: &[u8], lease: LeaseId) -> Result<LockGuard, Error> {
+        loop {
+            let revision = match self.try_acquire(holder, lease).await? {
+                Ok(guard) => return Ok(guard),
+                Err(revision) => revision,
+            };
+            // events since the holder acquired are retained, release is not missed
+            let mut watcher = match self.conf.kv_watch_bytes(&self.key, revision + 1).await {
+                Ok(watcher) => watcher,
+                Err(Error::RevisionCompacted(_)) => {
+                    // watch new events, a release before the watch is seen by acquiring again
+                    let watcher = self.conf.kv_watch_bytes(&self.key, 0).await?;
+                    match self.try_acquire(holder, lease).await? {
+                        Ok(guard) => return Ok(guard),
+                        Err(_) => watcher,
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            self.wait_release(&mut watcher, lease).await?;
+        }
+    }
+
+    /// Wait until lock key is deleted, keep `lease` alive meanwhile.
+    async fn wait_release(&self, watcher: &mut KvWatcher, lease: LeaseId) -> Result<(), Error> {
+        let interval = self.keep_alive_interval();
+        let mut deadline = Instant::now() + interval;
+        loop {
+            let remain = deadline.saturating_duration_since(Instant::now());
+            match madsim::time::timeout(remain, watcher.next()).await {
+                Ok(Some(KvEvent::Delete(key, _))) if key == self.key => return Ok(()),
+                Ok(Some(_)) => {}
+                // watch is closed, check lock again
+                Ok(None) => return Ok(()),
+                Err(_) => {
+                    self.conf.lease_keep_alive(lease).await?;
+                    deadline = Instant::now() + interval;
+                }
+            }
+        }
+    }
+
+    /// Acquire lock with `lease`, return mod revision of lock key if held by others.
+    async fn try_acquire(
+        &self,
+        holder: &[u8],
+        lease: LeaseId,
+    ) -> Result<Result<LockGuard, u64>, Error> {
+        let res = self
+            .conf
+            .txn(
+                vec![Compare::RevisionEquals(self.key.clone(), 0)],
+                vec![TxnOp::PutLease(self.key.clone(), holder.to_vec(), lease)],
+                vec![TxnOp::Get(self.key.clone())],
+            )
+            .await?;
+        match res.results[..] {
+            [TxnOpResult::Put(token)] => Ok(Ok(LockGuard {
+                key: self.key.clone(),
+                lease,
+                token,
+            })),
+            [TxnOpResult::Get(Some((_, revision)))] => Ok(Err(revision)),
+            // txn is atomic, lock key exists if compare fails
+            _ => Err(Error::TxnConflict),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctl::conf::tests::local_conf;
+    use crate::ctl::replica::tests::local_replica;
+    use crate::ctl::Rconf;
+    use crate::storage_mod::BasicEngine;
+    use madsim::time::sleep;
+    use tempfile::TempDir;
+
+    const TTL: Duration = Duration::from_secs(3);
+
+    /// Conf on a single node replica which expires leases.
+    fn expiring_conf() -> (Rconf<BasicEngine>, TempDir) {
+        let (replica, path) = local_replica();
+        let runner = replica.clone();
+        let interval = Duration::from_millis(500);
+        madsim::task::spawn(async move { runner.run_leader_tick(interval).await }).detach();
+        (Rconf::new(replica), path)
+    }
+
+    #[madsim::test]
+    async fn test_try_lock() {
+        let (conf, _path) = local_conf();
+        let lock = ConfLock::new(&conf, "leader", TTL);
+        assert_eq!(lock.holder().await.unwrap(), None);
+
+        let guard = lock.try_lock(b"a").await.unwrap().unwrap();
+        assert_eq!(
+            lock.holder().await.unwrap(),
+            Some((b"a".to_vec(), guard.token()))
+        );
+        assert!(lock.try_lock(b"b").await.unwrap().is_none());
+        // lock of another name is independent, even if it shares the prefix
+        let other = ConfLock::new(&conf, "leader2", TTL);
+        let other_guard = other.try_lock(b"b").await.unwrap().unwrap();
+
+        let token = guard.token();
+        lock.unlock(guard).await.unwrap();
+        assert_eq!(lock.holder().await.unwrap(), None);
+        let guard = lock.try_lock(b"b").await.unwrap().unwrap();
+        assert!(guard.token() > token);
+        assert!(guard.token() > other_guard.token());
+        lock.unlock(guard).await.unwrap();
+        other.unlock(other_guard).await.unwrap();
+    }
+
+    #[madsim::test]
+    async fn test_lock_wait() {
+        let (conf, _path) = expiring_conf();
+        let lock = ConfLock::new(&conf, "leader", TTL);
+        let guard = lock.lock(b"a").await.unwrap();
+        let token = guard.token();
+
+        let start = Instant::now();
+        let hold = async {
+            // waiter keeps its lease alive while waiting well past ttl
+            for _ in 0..5 {
+                sleep(lock.keep_alive_interval()).await;
+                lock.keep_alive(&guard).await.unwrap();
+            }
+            lock.unlock(guard).await.unwrap();
+        };
+        let (_, guard) = tokio::join!(hold, lock.lock(b"b"));
+        let guard = guard.unwrap();
+        assert!(start.elapsed() > TTL);
+        assert!(guard.token() > token);
+        assert_eq!(
+            lock.holder().await.unwrap(),
+            Some((b"b".to_vec(), guard.token()))
+        );
+
+        // lost if holder stops keeping it alive
+        sleep(TTL * 2).await;
+        assert_eq!(lock.holder().await.unwrap(), None);
+        assert!(matches!(
+            lock.keep_alive(&guard).await,
+            Err(Error::LeaseNotFound(_))
+        ));
+        lock.unlock(guard).await.unwrap();
+    }
+
+    #[madsim::test]
+    async fn test_lock_expired_holder() {
+        let (conf, _path) = expiring_conf();
+        let lock = ConfLock::new(&conf, "leader", TTL);
+        let dead = lock.lock(b"a").await.unwrap();
+
+        let start = Instant::now();
+        let guard = lock.lock(b"b").await.unwrap();
+        assert!(start.elapsed() >= TTL);
+        assert!(guard.token() > dead.token());
+        assert_eq!(
+            lock.holder().await.unwrap(),
+            Some((b"b".to_vec(), guard.token()))
+        );
+    }
+
+    #[madsim::test]
+    async fn test_lock_compacted() {
+        let (conf, _path) = expiring_conf();
+        let lock = ConfLock::new(&conf, "leader", TTL);
+        let guard = lock.lock(b"a").await.unwrap();
+        // events since the lock was acquired are out of watch window
+        for i in 0..200 {
+            conf.kv_put(&format!("other/{}", i), "").await.unwrap();
+        }
+
+        let hold = async {
+            sleep(lock.keep_alive_interval()).await;
+            lock.keep_alive(&guard).await.unwrap();
+            sleep(lock.keep_alive_interval()).await;
+            lock.unlock(guard).await.unwrap();
+        };
+        let (_, guard) = tokio::join!(hold, lock.lock(b"b"));
+        assert_eq!(
+            lock.holder().await.unwrap(),
+            Some((b"b".to_vec(), guard.unwrap().token()))
+        );
+    }
+}
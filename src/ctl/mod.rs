@@ -1,7 +1,23 @@
 pub mod client_ctl;
 pub mod server_ctl;
 pub mod conf;
+pub mod liveness;
+pub mod history;
+pub mod state_machine;
+pub mod oid_cache;
+pub mod watch;
+pub mod subscription;
+pub mod replica;
+pub mod lock;
 
 pub use client_ctl::*;
 pub use server_ctl::*;
-pub use conf::*;
\ No newline at end of file
+pub use conf::*;
+pub use liveness::*;
+pub use history::*;
+pub use state_machine::*;
+pub use oid_cache::*;
+pub use watch::*;
+pub use subscription::*;
+pub use replica::*;
+pub use lock::*;
\ No newline at end of file
@@ -0,0 +1,145 @@
+use std::sync::Mutex;
+use crate::{storage_mod::KvEngine, Error};
+use super::{Command, CommandResult, Replica};
+
+/// Oids pre-allocated by a server.
+///
+/// Server allocates oids from controller in large blocks and serves most
+/// `oid_alloc` calls from the cached block without a raft round trip.
+/// Oids left in cache are lost on restart, they are never reused.
+pub struct OidCache {
+    block_size: u64,
+    /// Cached oids `[start, end)`
+    range: Mutex<(u64, u64)>,
+}
+
+impl OidCache {
+    pub fn new(block_size: u64) -> OidCache {
+        OidCache {
+            block_size,
+            range: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Take `cnt` contiguous oids from cache, None if not enough cached.
+    pub fn take(&self, cnt: u64) -> Option<(u64, u64)> {
+        let mut range = self.range.lock().unwrap();
+        if range.1 - range.0 < cnt {
+            return None;
+        }
+        let start = range.0;
+        range.0 += cnt;
+        Some((start, range.0))
+    }
+
+    /// Number of oids to allocate from controller for a `cnt` miss.
+    pub fn block_for(&self, cnt: u64) -> u64 {
+        cnt.max(self.block_size)
+    }
+
+    /// Cache block `[start, end)` allocated from controller and take `cnt` oids from it.
+    ///
+    /// Block contiguous with cached oids extends them, otherwise cached oids are dropped.
+    pub fn refill(&self, start: u64, end: u64, cnt: u64) -> (u64, u64) {
+        debug_assert!(end - start >= cnt);
+        let mut range = self.range.lock().unwrap();
+        if range.1 != start {
+            range.0 = start;
+        }
+        range.1 = end;
+        let start = range.0;
+        range.0 += cnt;
+        (start, range.0)
+    }
+
+    /// Alloc `cnt` contiguous oids, propose `Command::AllocOid` through `replica` on miss.
+    ///
+    /// `Error::InvalidArg` if `cnt` is 0.
+    pub async fn alloc<EK: KvEngine>(
+        &self,
+        replica: &Replica<EK>,
+        cnt: u64,
+    ) -> Result<(u64, u64), Error> {
+        if cnt == 0 {
+            return Err(Error::InvalidArg);
+        }
+        if let Some(range) = self.take(cnt) {
+            return Ok(range);
+        }
+        let block = self.block_for(cnt);
+        match replica.propose(Command::AllocOid(block)).await? {
+            CommandResult::OidRange(start, end) => Ok(self.refill(start, end, cnt)),
+            res => unreachable!("AllocOid returns OidRange, got {:?}", res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_mod::BasicEngine;
+    use crate::ctl::replica::tests::local_replica;
+
+    #[test]
+    fn test_oid_cache() {
+        let cache = OidCache::new(100);
+        assert_eq!(cache.take(1), None);
+        assert_eq!(cache.block_for(1), 100);
+        assert_eq!(cache.block_for(1000), 1000);
+        assert_eq!(cache.refill(0, 100, 1), (0, 1));
+        assert_eq!(cache.take(90), Some((1, 91)));
+        assert_eq!(cache.take(10), None);
+        // contiguous block extends cached oids
+        assert_eq!(cache.refill(100, 200, 10), (91, 101));
+        // other servers allocated in between
+        assert_eq!(cache.refill(500, 600, 50), (500, 550));
+        assert_eq!(cache.take(50), Some((550, 600)));
+    }
+
+    /// Alloc 20 ranges of varying size from `cache`.
+    async fn alloc_all(cache: &OidCache, replica: &Replica<BasicEngine>) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        for i in 0..20 {
+            let cnt = i % 7 + 1;
+            let range = cache.alloc(replica, cnt).await.unwrap();
+            assert_eq!(range.1 - range.0, cnt);
+            ranges.push(range);
+        }
+        ranges
+    }
+
+    #[madsim::test]
+    async fn test_oid_alloc() {
+        let (replica, _path) = local_replica();
+        let (cache0, cache1) = (OidCache::new(10), OidCache::new(10));
+        // two servers allocate concurrently, their misses interleave in raft log
+        let (mut ranges, other) =
+            tokio::join!(alloc_all(&cache0, &replica), alloc_all(&cache1, &replica));
+        ranges.extend(other);
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            assert!(
+                pair[0].1 <= pair[1].0,
+                "{:?} overlaps {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert!(matches!(
+            cache0.alloc(&replica, u64::MAX).await,
+            Err(Error::OidExhausted)
+        ));
+
+        // empty ranges are rejected, with or without cached oids
+        let cache = OidCache::new(10);
+        assert!(matches!(
+            cache.alloc(&replica, 0).await,
+            Err(Error::InvalidArg)
+        ));
+        cache.alloc(&replica, 1).await.unwrap();
+        assert!(matches!(
+            cache.alloc(&replica, 0).await,
+            Err(Error::InvalidArg)
+        ));
+    }
+}
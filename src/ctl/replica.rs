@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use madsim::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use crate::storage_mod::KvEngine;
+use crate::{
+    cluster::ChangeLogError, ChangeLog, ClusterMap, ClusterMapVersion, Codec, Error, LeaseId,
+    Revision, TargetId,
+};
+use super::{
+    Command, CommandResult, DeadlineTracker, LivenessPolicy, LivenessTracker, MapHistory,
+    MapPublisher, RetentionPolicy, StateMachine, WatchHub,
+};
+
+/// Committed raft log entry, `(revision, data)`
+pub type CommittedEntry = (Revision, Vec<u8>);
+
+/// Raft log replicating controller commands.
+///
+/// Committed entries are delivered to `Replica::run` in log order,
+/// entries with empty data are no-ops, eg: appended by a new leader.
+pub trait RaftLog: Send + Sync {
+    /// Append `data` to log, return revision it is committed at unless dropped.
+    ///
+    /// Return `Error::LeadershipLost` if this node is not leader.
+    fn propose(&self, data: Vec<u8>) -> Result<Revision, Error>;
+
+    /// Whether this node is leader.
+    fn is_leader(&self) -> bool;
+
+    /// Current term, a node elected leader again is in a new term.
+    fn term(&self) -> u64;
+}
+
+/// Raft log of a single node controller, entries are committed once proposed.
+pub struct LocalLog {
+    /// Revision of the last proposed entry.
+    last: Mutex<Revision>,
+    tx: mpsc::UnboundedSender<CommittedEntry>,
+}
+
+impl LocalLog {
+    /// Log after `applied`, committed entries are sent to the returned receiver.
+    ///
+    /// Each start is a new term, like a node elected leader again.
+    pub fn new(applied: Revision) -> (LocalLog, mpsc::UnboundedReceiver<CommittedEntry>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let last = Revision::new(applied.index, applied.term + 1);
+        let log = LocalLog {
+            last: Mutex::new(last),
+            tx,
+        };
+        (log, rx)
+    }
+}
+
+impl RaftLog for LocalLog {
+    fn propose(&self, data: Vec<u8>) -> Result<Revision, Error> {
+        let mut last = self.last.lock().unwrap();
+        let revision = Revision::new(last.index + 1, last.term);
+        self.tx
+            .send((revision, data))
+            .map_err(|_| Error::ProposalDropped)?;
+        *last = revision;
+        Ok(revision)
+    }
+
+    fn is_leader(&self) -> bool {
+        true
+    }
+
+    fn term(&self) -> u64 {
+        self.last.lock().unwrap().term
+    }
+}
+
+/// Proposal waiting to be applied, `(term, result sender)`
+type Proposal = (u64, oneshot::Sender<Result<CommandResult, Error>>);
+
+/// State only kept on leader, rebuilt when this node is elected.
+struct LeaderState {
+    /// Term this node is leader of.
+    term: u64,
+    /// Keep-alives of granted leases, counted from the election.
+    leases: DeadlineTracker<LeaseId>,
+    /// Heartbeats of targets, counted from the election.
+    liveness: LivenessTracker,
+}
+
+impl LeaderState {
+    fn new(term: u64, liveness: LivenessPolicy, now: Instant) -> LeaderState {
+        LeaderState {
+            term,
+            leases: DeadlineTracker::new(now),
+            liveness: LivenessTracker::new(liveness, now),
+        }
+    }
+}
+
+/// Controller replica, proposes commands and applies committed ones to `StateMachine`.
+///
+/// Proposals are resolved when the entry at their revision is applied:
+/// with the result of the command, or `Error::ProposalDropped` if another
+/// entry is committed there, eg: after leader changed.
+pub struct Replica<EK: KvEngine> {
+    log: Box<dyn RaftLog>,
+    state_machine: RwLock<StateMachine<EK>>,
+    /// Waiting proposals by raft index.
+    proposals: Mutex<BTreeMap<u64, Proposal>>,
+    /// Maps committed since this replica started, within retention policy.
+    history: Mutex<MapHistory>,
+    /// Conf key changes are published here as commands are applied.
+    watch_hub: Arc<WatchHub>,
+    /// Committed changelogs are published here for map subscribers.
+    publisher: Arc<MapPublisher>,
+    /// State of the term this node is leader of, None if it hasn't acted as leader.
+    leader: Mutex<Option<LeaderState>>,
+    /// Timeouts to mark targets DOWN and OUT on leader.
+    liveness: LivenessPolicy,
+}
+
+impl<EK: KvEngine> Replica<EK> {
+    /// Replica of state machine persisted in `engine`, `log` continues after its applied revision.
+    ///
+    /// Committed maps are retained by `policy`, history starts from the persisted map.
+    /// Map subscribers get deltas of the same number of changelogs.
+    /// Targets are marked DOWN and OUT by `liveness` when this node is leader.
+    /// The latest `watch_window` conf key changes are retained for resuming watches.
+    pub fn new(
+        log: Box<dyn RaftLog>,
+        engine: EK,
+        policy: RetentionPolicy,
+        liveness: LivenessPolicy,
+        watch_window: usize,
+    ) -> Replica<EK> {
+        let state_machine = StateMachine::new(engine);
+        let history = MapHistory::new(state_machine.map(), policy);
+        let watch_hub = WatchHub::new(watch_window, state_machine.applied());
+        let publisher = MapPublisher::new(state_machine.map(), policy.max_logs);
+        Replica {
+            log,
+            state_machine: RwLock::new(state_machine),
+            proposals: Mutex::new(BTreeMap::new()),
+            history: Mutex::new(history),
+            watch_hub: Arc::new(watch_hub),
+            publisher: Arc::new(publisher),
+            leader: Mutex::new(None),
+            liveness,
+        }
+    }
+
+    /// Read local state machine, may be stale on followers.
+    pub fn state_machine(&self) -> std::sync::RwLockReadGuard<'_, StateMachine<EK>> {
+        self.state_machine.read().unwrap()
+    }
+
+    /// Cluster map applied by this replica.
+    pub fn current_map(&self) -> Arc<ClusterMap> {
+        self.state_machine().map()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.log.is_leader()
+    }
+
+    /// Watches of conf key changes applied by this replica.
+    pub fn watch_hub(&self) -> &WatchHub {
+        &self.watch_hub
+    }
+
+    /// Map updates applied by this replica, serve them with `MapPublisher::serve`.
+    pub fn publisher(&self) -> &Arc<MapPublisher> {
+        &self.publisher
+    }
+
+    /// Cluster map of `version` from retained history, None if not retained or never exists.
+    pub fn map_at(&self, version: ClusterMapVersion) -> Option<Arc<ClusterMap>> {
+        self.history.lock().unwrap().map_at(version)
+    }
+
+    /// Propose `cmd` and wait until it is applied, return its result.
+    ///
+    /// Return `Error::ProposalDropped` if it is not committed, it is safe to retry.
+    pub async fn propose(&self, cmd: Command) -> Result<CommandResult, Error> {
+        let rx = {
+            // registered before the entry can be applied
+            let mut proposals = self.proposals.lock().unwrap();
+            let revision = self.log.propose(cmd.encode())?;
+            let (tx, rx) = oneshot::channel();
+            proposals.insert(revision.index, (revision.term, tx));
+            rx
+        };
+        rx.await.map_err(|_| Error::ProposalDropped)?
+    }
+
+    /// Run `f` on leader state, rebuild it first if this node is elected in a new term.
+    ///
+    /// Return `Error::LeadershipLost` on followers.
+    fn with_leader<T>(&self, f: impl FnOnce(&mut LeaderState) -> T) -> Result<T, Error> {
+        let mut leader = self.leader.lock().unwrap();
+        if !self.is_leader() {
+            *leader = None;
+            return Err(Error::LeadershipLost);
+        }
+        let term = self.log.term();
+        let state = match leader.take() {
+            Some(state) if state.term == term => state,
+            // keep-alives went to the previous leader, count from now
+            _ => LeaderState::new(term, self.liveness, Instant::now()),
+        };
+        Ok(f(leader.insert(state)))
+    }
+
+    /// Record keep-alive of lease `id`, only leader tracks leases.
+    ///
+    /// Return `Error::LeadershipLost` on followers,
+    /// `Error::LeaseNotFound` if it is revoked or expired.
+    pub fn lease_keep_alive(&self, id: LeaseId) -> Result<(), Error> {
+        if self.state_machine().lease_ttl(id).is_none() {
+            return Err(Error::LeaseNotFound(id));
+        }
+        self.with_leader(|leader| leader.leases.touch(id, Instant::now()))
+    }
+
+    /// Propose `Command::LeaseRevoke` for leases not kept alive within their ttl, on leader.
+    ///
+    /// Return ids of revoked leases. Leases not kept alive since this node
+    /// is elected get a full ttl from the election.
+    pub async fn expire_leases(&self) -> Result<Vec<LeaseId>, Error> {
+        let leases = self.state_machine().leases()?;
+        let now = Instant::now();
+        let expired = match self.with_leader(|leader| leader.leases.expired(leases, now)) {
+            Ok(expired) => expired,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let mut revoked = Vec::new();
+        for id in expired {
+            match self.propose(Command::LeaseRevoke(id)).await {
+                Ok(_) => revoked.push(id),
+                // revoked by its owner meanwhile
+                Err(Error::LeaseNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(revoked)
+    }
+
+    /// Record heartbeat of target `id`, only leader tracks targets.
+    ///
+    /// Return `Error::LeadershipLost` on followers.
+    pub fn heartbeat(&self, id: TargetId) -> Result<(), Error> {
+        self.with_leader(|leader| leader.liveness.heartbeat(id, Instant::now()))
+    }
+
+    /// Propose changelog marking targets without heartbeat DOWN, and DOWN ones OUT, on leader.
+    ///
+    /// Return map after it, or current map on followers and if no target expires.
+    /// Targets in maintenance are skipped, see `TargetInfo::allow_auto_down`.
+    pub async fn check_liveness(&self) -> Result<Arc<ClusterMap>, Error> {
+        self.change_map(|map| {
+            let now = Instant::now();
+            let expired = self.with_leader(|leader| {
+                let down = leader.liveness.expired_up(map, now);
+                (down, leader.liveness.expired_in(map, now))
+            });
+            match expired {
+                Ok((down, out)) => Ok(map.mark_down(&down, &out)),
+                Err(_) => Ok(None),
+            }
+        })
+        .await
+    }
+
+    /// Run duties of leader every `interval` until dropped: check liveness of targets
+    /// and expire leases. Nothing is done on followers, errors are retried next round.
+    pub async fn run_leader_tick(&self, interval: Duration) {
+        loop {
+            madsim::time::sleep(interval).await;
+            let _ = self.check_liveness().await;
+            let _ = self.expire_leases().await;
+        }
+    }
+
+    /// Propose changelog built by `change` from current map, return map after it.
+    ///
+    /// Return current map if `change` returns None. Rebuild and retry
+    /// if another changelog is committed first.
+    pub async fn change_map<F>(&self, change: F) -> Result<Arc<ClusterMap>, Error>
+    where
+        F: Fn(&ClusterMap) -> Result<Option<ChangeLog>, Error> + Send,
+    {
+        loop {
+            let map = self.current_map();
+            let log = match change(&map)? {
+                Some(log) => log,
+                None => return Ok(map),
+            };
+            match self.propose(Command::ChangeMap(log)).await {
+                Ok(_) => return Ok(self.current_map()),
+                Err(Error::InvalidChangeLog(ChangeLogError::VersionGap(..))) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Apply entry committed at `revision`, resolve the proposal waiting for it.
+    pub fn apply(&self, revision: Revision, data: &[u8]) {
+        let res = if data.is_empty() {
+            None
+        } else {
+            match Command::decode(data) {
+                Ok(cmd) => {
+                    let mut state_machine = self.state_machine.write().unwrap();
+                    let res = state_machine.apply(revision, &cmd).transpose();
+                    match (&cmd, &res) {
+                        (Command::ChangeMap(log), Some(Ok(_))) => {
+                            self.record_map(&state_machine, log, revision);
+                        }
+                        // grant counts as the first keep-alive
+                        (Command::LeaseGrant(_), Some(Ok(CommandResult::Granted(id)))) => {
+                            if let Some(leader) = self.leader.lock().unwrap().as_mut() {
+                                leader.leases.touch(*id, Instant::now());
+                            }
+                        }
+                        (Command::LeaseRevoke(id), Some(Ok(_))) => {
+                            if let Some(leader) = self.leader.lock().unwrap().as_mut() {
+                                leader.leases.forget(*id);
+                            }
+                        }
+                        _ => {}
+                    }
+                    // published in apply order, watchers never see a later event first
+                    let events = state_machine.take_events();
+                    if !events.is_empty() {
+                        self.watch_hub.publish(revision, events);
+                    }
+                    res
+                }
+                Err(e) => Some(Err(e.into())),
+            }
+        };
+
+        let mut proposals = self.proposals.lock().unwrap();
+        // proposals before this entry are never committed, eg: overwritten by a new leader
+        while let Some(entry) = proposals.first_entry() {
+            if *entry.key() >= revision.index {
+                break;
+            }
+            let _ = entry.remove().1.send(Err(Error::ProposalDropped));
+        }
+        if let Some((term, tx)) = proposals.remove(&revision.index) {
+            let res = match res {
+                Some(res) if term == revision.term => res,
+                _ => Err(Error::ProposalDropped),
+            };
+            let _ = tx.send(res);
+        }
+    }
+
+    /// Record map changed by `log` in history and publish it, it must follow the latest one.
+    fn record_map(&self, state_machine: &StateMachine<EK>, log: &ChangeLog, revision: Revision) {
+        let mut history = self.history.lock().unwrap();
+        let now = madsim::time::Instant::now();
+        if history.append(log.clone(), revision, now).is_err() {
+            // never happens unless history is out of sync, restart from applied map
+            *history = MapHistory::new(state_machine.map(), history.policy());
+        }
+        self.publisher.publish(log.clone(), state_machine.map());
+    }
+
+    /// Apply committed entries from `entries` until it is closed.
+    pub async fn run(&self, mut entries: mpsc::UnboundedReceiver<CommittedEntry>) {
+        while let Some((revision, data)) = entries.recv().await {
+            self.apply(revision, &data);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::storage_mod::BasicEngine;
+    use crate::{TargetChangeOp, TargetLocation};
+    use rocksdb::DB;
+    use std::net::SocketAddr;
+    use tempfile::{Builder, TempDir};
+    use uuid::Uuid;
+
+    const POLICY: RetentionPolicy = RetentionPolicy {
+        max_logs: 4,
+        max_age: std::time::Duration::from_secs(3600),
+        checkpoint_interval: 2,
+    };
+
+    const LIVENESS: LivenessPolicy = LivenessPolicy {
+        down_timeout: Duration::from_secs(10),
+        out_timeout: Duration::from_secs(60),
+    };
+
+    /// Single node replica on a temp dir, applying entries in background.
+    pub(crate) fn local_replica() -> (Arc<Replica<BasicEngine>>, TempDir) {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(&path).unwrap()));
+        let (log, entries) = LocalLog::new(Revision::default());
+        let replica = Arc::new(Replica::new(Box::new(log), engine, POLICY, LIVENESS, 128));
+        let runner = replica.clone();
+        madsim::task::spawn(async move { runner.run(entries).await }).detach();
+        (replica, path)
+    }
+
+    /// Log that only records proposals, tests apply entries by hand.
+    struct ManualLog {
+        proposed: Mutex<Vec<CommittedEntry>>,
+        term: u64,
+    }
+
+    impl RaftLog for ManualLog {
+        fn propose(&self, data: Vec<u8>) -> Result<Revision, Error> {
+            let mut proposed = self.proposed.lock().unwrap();
+            let revision = Revision::new(proposed.len() as u64 + 1, self.term);
+            proposed.push((revision, data));
+            Ok(revision)
+        }
+
+        fn is_leader(&self) -> bool {
+            true
+        }
+
+        fn term(&self) -> u64 {
+            self.term
+        }
+    }
+
+    /// Local log whose leadership is switched by tests, `(is leader, term)`
+    struct SwitchLog {
+        log: LocalLog,
+        leadership: Arc<Mutex<(bool, u64)>>,
+    }
+
+    impl RaftLog for SwitchLog {
+        fn propose(&self, data: Vec<u8>) -> Result<Revision, Error> {
+            if !self.is_leader() {
+                return Err(Error::LeadershipLost);
+            }
+            self.log.propose(data)
+        }
+
+        fn is_leader(&self) -> bool {
+            self.leadership.lock().unwrap().0
+        }
+
+        fn term(&self) -> u64 {
+            self.leadership.lock().unwrap().1
+        }
+    }
+
+    #[madsim::test]
+    async fn test_propose() {
+        let (replica, _path) = local_replica();
+        assert_eq!(
+            replica.propose(Command::AllocOid(10)).await.unwrap(),
+            CommandResult::OidRange(0, 10)
+        );
+        assert!(matches!(
+            replica.propose(Command::AllocOid(0)).await,
+            Err(Error::InvalidArg)
+        ));
+
+        let uuid = Uuid::new_v4();
+        let location = TargetLocation::with_host("host", "dev").unwrap();
+        let create = TargetChangeOp::Create(0, None, location, Default::default());
+        let build = |map: &ClusterMap| map.change_builder().add(uuid, create.clone()).build();
+        let map = replica
+            .change_map(|map| Ok(Some(build(map)?)))
+            .await
+            .unwrap();
+        assert_eq!(map.get_target_by_uuid(&uuid).unwrap().get_id(), Some(0));
+        assert_eq!(map.revision, replica.state_machine().applied());
+        // changelog built from a stale map is rejected
+        let stale = build(&ClusterMap::new_initial()).unwrap();
+        assert!(matches!(
+            replica.propose(Command::ChangeMap(stale)).await,
+            Err(Error::InvalidChangeLog(ChangeLogError::VersionGap(..)))
+        ));
+        assert_eq!(replica.current_map(), map);
+    }
+
+    #[madsim::test]
+    async fn test_proposal_dropped() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(&path).unwrap()));
+        let log = ManualLog {
+            proposed: Mutex::new(Vec::new()),
+            term: 1,
+        };
+        let replica = Replica::new(Box::new(log), engine, POLICY, LIVENESS, 128);
+        let proposals = async {
+            let first = replica.propose(Command::AllocOid(1)).await;
+            let second = replica.propose(Command::AllocOid(2)).await;
+            (first, second)
+        };
+        let apply = async {
+            // new leader overwrites index 1 with a no-op in term 2
+            madsim::time::sleep(std::time::Duration::from_millis(1)).await;
+            replica.apply(Revision::new(1, 2), &[]);
+            madsim::time::sleep(std::time::Duration::from_millis(1)).await;
+            let data = Command::AllocOid(2).encode();
+            replica.apply(Revision::new(2, 1), &data);
+        };
+        let ((first, second), ()) = tokio::join!(proposals, apply);
+        assert!(matches!(first, Err(Error::ProposalDropped)));
+        assert_eq!(second.unwrap(), CommandResult::OidRange(0, 2));
+    }
+
+    #[madsim::test]
+    async fn test_map_at() {
+        let (replica, _path) = local_replica();
+        let initial = replica.current_map();
+        let mut maps = vec![initial.clone()];
+        for i in 0..6u32 {
+            let uuid = Uuid::from_u128(i as u128);
+            let location = TargetLocation::with_host("host", "dev").unwrap();
+            let create = TargetChangeOp::Create(i, None, location, Default::default());
+            let build = |map: &ClusterMap| map.change_builder().add(uuid, create.clone()).build();
+            let map = replica
+                .change_map(|map| Ok(Some(build(map)?)))
+                .await
+                .unwrap();
+            maps.push(map);
+        }
+        // other commands don't change map
+        replica.propose(Command::AllocOid(1)).await.unwrap();
+
+        // last 4 changelogs are retained, rounded to checkpoint of maps[2]
+        assert!(replica.map_at(initial.version).is_none());
+        assert!(replica.map_at(maps[1].version).is_none());
+        for map in &maps[2..] {
+            assert_eq!(&replica.map_at(map.version).unwrap(), map);
+        }
+        let next = maps[6].version.next_minor();
+        assert!(replica.map_at(next).is_none());
+    }
+    #[madsim::test]
+    async fn test_lease_failover() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(&path).unwrap()));
+        let (log, entries) = LocalLog::new(Revision::default());
+        let leadership = Arc::new(Mutex::new((true, 1)));
+        let log = SwitchLog {
+            log,
+            leadership: leadership.clone(),
+        };
+        let replica = Arc::new(Replica::new(Box::new(log), engine, POLICY, LIVENESS, 128));
+        let runner = replica.clone();
+        madsim::task::spawn(async move { runner.run(entries).await }).detach();
+
+        let ttl = Duration::from_secs(3);
+        let id = match replica.propose(Command::LeaseGrant(ttl)).await.unwrap() {
+            CommandResult::Granted(id) => id,
+            res => panic!("unexpected {:?}", res),
+        };
+        // another node leads for a while, holder keeps the lease alive there
+        *leadership.lock().unwrap() = (false, 2);
+        madsim::time::sleep(ttl * 3).await;
+        assert!(matches!(
+            replica.lease_keep_alive(id),
+            Err(Error::LeadershipLost)
+        ));
+        assert!(replica.expire_leases().await.unwrap().is_empty());
+
+        // elected again, the lease gets a full ttl from the election
+        *leadership.lock().unwrap() = (true, 3);
+        assert!(replica.expire_leases().await.unwrap().is_empty());
+        madsim::time::sleep(Duration::from_secs(2)).await;
+        replica.lease_keep_alive(id).unwrap();
+        madsim::time::sleep(Duration::from_secs(2)).await;
+        assert!(replica.expire_leases().await.unwrap().is_empty());
+        madsim::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(replica.expire_leases().await.unwrap(), vec![id]);
+    }
+    #[madsim::test]
+    async fn test_liveness() {
+        let (replica, _path) = local_replica();
+        replica
+            .change_map(|map| {
+                let mut builder = map.change_builder();
+                for i in 0..3 {
+                    let uuid = Uuid::from_u128(i as u128);
+                    let url = SocketAddr::from(([10, 0, 0, i as u8], 8000));
+                    let location = TargetLocation::with_host(&format!("host{}", i), "dev").unwrap();
+                    let create = TargetChangeOp::Create(i, Some(url), location, Default::default());
+                    builder = builder.add(uuid, create).add(uuid, TargetChangeOp::In);
+                }
+                Ok(Some(builder.build()?))
+            })
+            .await
+            .unwrap();
+        let path = "/default/default/default/host2";
+        replica
+            .change_map(|map| Ok(map.set_maintenance(path, true)))
+            .await
+            .unwrap();
+        let runner = replica.clone();
+        let tick = Duration::from_secs(1);
+        madsim::task::spawn(async move { runner.run_leader_tick(tick).await }).detach();
+
+        // target 0 keeps sending heartbeat, 1 stops, 2 is in maintenance
+        let alive = |secs: u64| {
+            let replica = replica.clone();
+            async move {
+                for _ in 0..secs {
+                    replica.heartbeat(0).unwrap();
+                    madsim::time::sleep(tick).await;
+                }
+            }
+        };
+        let state = |id| {
+            let map = replica.current_map();
+            let target = map.get_target(id).unwrap();
+            (target.is_up(), target.is_in())
+        };
+        alive(5).await;
+        assert_eq!([state(0), state(1), state(2)], [(true, true); 3]);
+        alive(7).await;
+        assert_eq!(state(1), (false, true));
+        alive(60).await;
+        assert_eq!(state(1), (false, false));
+        assert_eq!([state(0), state(2)], [(true, true); 2]);
+    }
+}
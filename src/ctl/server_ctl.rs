@@ -1,11 +1,12 @@
 use std::net::SocketAddr;
 use std::sync::{RwLock, Arc};
+use std::time::Duration;
 use crate::{storage_mod::{
     KvEngine, RaftEngine, Peer
-}, ClusterMap, ServerCtl, Conf, ClusterMapVersion, Error};
+}, cluster::ChangeLogError, ClusterMap, ServerCtl, Conf, ClusterMapVersion, Error, TargetCapacity};
 use async_trait::async_trait;
 use uuid::Uuid;
-use super::Rconf;
+use super::{Rconf, OidCache, Replica};
 
 
 pub struct RServerCtl<EK, ER>
@@ -16,11 +17,37 @@ where
     uuid: Uuid,
     url: SocketAddr,
     raft_peer: Peer<EK, ER>,
-    conf: Rconf,
+    /// Local replica of controller state, driven by `raft_peer`.
+    replica: Arc<Replica<EK>>,
+    conf: Rconf<EK>,
     curr_map: RwLock<Arc<ClusterMap>>,
+    /// Oids pre-allocated from controller.
+    oid_cache: OidCache,
 
 }
 
+impl<EK, ER> RServerCtl<EK, ER>
+where
+    EK: KvEngine,
+    ER: RaftEngine,
+{
+    /// Create server ctl on `replica` and serve map subscriptions of clients on local endpoint.
+    ///
+    /// Subscription polls are held for at most `poll_timeout`, oids are allocated in blocks of `oid_block`.
+    pub fn new(uuid: Uuid, url: SocketAddr, raft_peer: Peer<EK, ER>, replica: Arc<Replica<EK>>, oid_block: u64, poll_timeout: Duration) -> RServerCtl<EK, ER>{
+        replica.publisher().serve(poll_timeout);
+        RServerCtl{
+            uuid,
+            url,
+            raft_peer,
+            conf: Rconf::new(replica.clone()),
+            curr_map: RwLock::new(replica.current_map()),
+            oid_cache: OidCache::new(oid_block),
+            replica,
+        }
+    }
+}
+
 #[async_trait]
 impl<EK, ER> ServerCtl for RServerCtl<EK, ER>
 where 
@@ -28,8 +55,15 @@ where
     ER: RaftEngine,
 {
     /// Wait a new cluster map.
+    ///
+    /// Wait until `replica` applies a map newer than `prev_version`, see `MapPublisher::wait_map`.
     async fn wait_map(&self, prev_version: ClusterMapVersion) -> Arc<ClusterMap>{
-        todo!()
+        let map = self.replica.publisher().wait_map(prev_version).await;
+        let mut curr_map = self.curr_map.write().unwrap();
+        if map.version > curr_map.version {
+            *curr_map = map;
+        }
+        curr_map.clone()
     }
 
     /// Close server, stop background task and mark self as DOWN.
@@ -39,22 +73,43 @@ where
 
     /// Get current cluster map.
     fn current_map(&self) -> Arc<ClusterMap>{
-        todo!()
+        self.curr_map.read().unwrap().clone()
     }
 
     /// Update cluster map if we know new version exists.
+    ///
+    /// Take map applied by `replica`, it never goes back to an older version.
     async fn update_map(&self) -> Result<Arc<ClusterMap>, Error>{
-        todo!();
+        let map = self.replica.current_map();
+        let mut curr_map = self.curr_map.write().unwrap();
+        if map.version > curr_map.version {
+            *curr_map = map;
+        }
+        Ok(curr_map.clone())
     }
 
     /// Get conf client.
     fn get_conf(&self) -> &dyn Conf{
-        todo!();
+        &self.conf
     }
 
     /// Alloc some unique oid.
+    ///
+    /// Take oids from `oid_cache`, propose `Command::AllocOid` for a new block on miss.
     async fn oid_alloc(&self, cnt: u64) -> Result<(u64, u64), Error>{
-        todo!();
+        self.oid_cache.alloc(&self.replica, cnt).await
+    }
+
+    /// Report capacity of self.
+    ///
+    /// Changelog is proposed only if usage crosses a step, see `ClusterMap::report_capacity`.
+    async fn report_capacity(&self, capacity: TargetCapacity) -> Result<(), Error>{
+        self.replica.change_map(|map| {
+            let id = map.get_target_by_uuid(&self.uuid).and_then(|target| target.get_id())
+                .ok_or(Error::InvalidChangeLog(ChangeLogError::UnknownTarget(self.uuid)))?;
+            Ok(map.report_capacity(id, capacity))
+        }).await?;
+        Ok(())
     }
 }
 
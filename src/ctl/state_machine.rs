@@ -0,0 +1,936 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::storage_mod::KvEngine;
+use crate::{
+    ChangeLog, ClusterMap, ClusterMapVersion, Codec, Compare, Error, KvEvent, KvPage, LeaseId,
+    ObjectClass, OcKind, OcTable, Revision, TxnOp, TxnOpResult, TxnResponse,
+};
+
+/// Key of the next unallocated oid.
+const NEXT_OID_KEY: &[u8] = b"sys/next_oid";
+/// Key of the last applied revision.
+const APPLIED_KEY: &[u8] = b"sys/applied";
+/// Key of the current cluster map.
+const MAP_KEY: &[u8] = b"sys/map";
+/// Key of chunk types table.
+const OC_CHUNK_KEY: &[u8] = b"sys/oc/chunk";
+/// Key of stripe types table.
+const OC_STRIPE_KEY: &[u8] = b"sys/oc/stripe";
+/// Prefix of object classes, `sys/oc/class/{name}` -> class.
+const OC_CLASS_PREFIX: &[u8] = b"sys/oc/class/";
+/// Prefix of user keys in `Conf` namespace.
+const KV_PREFIX: &[u8] = b"kv/";
+/// Prefix of granted leases, `lease/{id}` -> ttl in millis.
+const LEASE_PREFIX: &[u8] = b"lease/";
+/// Prefix of keys attached to leases, `lease_keys/{id}{key}` -> empty.
+const LEASE_KEYS_PREFIX: &[u8] = b"lease_keys/";
+/// Prefix of lease of attached keys, `key_lease/{key}` -> id.
+const KEY_LEASE_PREFIX: &[u8] = b"key_lease/";
+
+/// Key, value and mod revision of a conf key.
+pub type KvItem = (Vec<u8>, Vec<u8>, u64);
+
+/// Command replicated through raft log, applied by every controller node.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Command {
+    /// Allocate contiguous oids, `(cnt)`
+    AllocOid(u64),
+    /// Put conf key, `(key, value)`
+    KvPut(Vec<u8>, Vec<u8>),
+    /// Delete conf key, `(key)`
+    KvDelete(Vec<u8>),
+    /// Put conf key if its mod revision matches, 0 for not exists, `(key, expected revision, value)`
+    KvCas(Vec<u8>, u64, Vec<u8>),
+    /// Run `success` ops if all compares hold, otherwise `failure` ops, `(compares, success, failure)`
+    Txn(Vec<Compare>, Vec<TxnOp>, Vec<TxnOp>),
+    /// Grant a lease, `(ttl)`
+    LeaseGrant(Duration),
+    /// Revoke a lease and delete keys attached to it, `(lease id)`
+    LeaseRevoke(LeaseId),
+    /// Put conf key and attach it to a lease, `(key, value, lease id)`
+    KvPutLease(Vec<u8>, Vec<u8>, LeaseId),
+    /// Apply changelog to cluster map, `(changelog)`
+    ChangeMap(ChangeLog),
+    /// Create chunk or stripe type, `(kind, chunk size or stripe count)`
+    OcCreate(OcKind, u32),
+    /// Deprecate chunk or stripe type, `(kind, id)`
+    OcDeprecate(OcKind, u8),
+    /// Delete deprecated chunk or stripe type no objects use, `(kind, id)`
+    OcDelete(OcKind, u8),
+    /// Count a new object as user of its types, `(stripe type, chunk type)`
+    OcAcquire(u8, u8),
+    /// Release types of a deleted object, `(stripe type, chunk type)`
+    OcRelease(u8, u8),
+    /// Create object class if its name is not used, `(class)`
+    OcCreateClass(ObjectClass),
+}
+
+impl Codec for Command {
+    const KIND: u8 = 4;
+}
+
+/// Result of applying a command.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CommandResult {
+    /// Allocated oids `[start, end)`
+    OidRange(u64, u64),
+    /// Key is modified, `(mod revision)`
+    Modified(u64),
+    /// Key is deleted, `(existed)`
+    Deleted(bool),
+    /// Transaction is executed
+    Txn(TxnResponse),
+    /// Lease is granted, `(lease id)`
+    Granted(LeaseId),
+    /// Lease is revoked, `(number of deleted keys)`
+    Revoked(usize),
+    /// Cluster map is changed, `(new version)`
+    MapChanged(ClusterMapVersion),
+    /// Chunk or stripe type is created, `(id)`
+    OcCreated(u8),
+    /// Chunk or stripe types or object classes are changed
+    OcChanged,
+    /// Types are acquired by a new object, `(stripe count, chunk size)`
+    OcAcquired(u32, u32),
+}
+
+/// Replicated state machine on top of `KvEngine`.
+///
+/// Every node applies the same commands in raft log order, so results are
+/// the same on all nodes and survive leader changes and restarts.
+///
+/// Writes of a command are committed with its revision in one batch,
+/// so a command is applied entirely or not at all.
+pub struct StateMachine<EK: KvEngine> {
+    engine: EK,
+    applied: Revision,
+    /// Writes of the applying command, None for delete.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Events of the applying command.
+    pending_events: Vec<KvEvent>,
+    /// Changes of conf keys not taken by watch hub yet.
+    events: Vec<KvEvent>,
+    map: Arc<ClusterMap>,
+    /// Cluster map changed by the applying command.
+    pending_map: Option<Arc<ClusterMap>>,
+}
+
+fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+fn decode_u64(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf.try_into().expect("corrupted u64 value"))
+}
+
+impl<EK: KvEngine> StateMachine<EK> {
+    /// Load state machine persisted in `engine`.
+    pub fn new(engine: EK) -> StateMachine<EK> {
+        let applied = match engine.get(APPLIED_KEY) {
+            Some(buf) => Revision::new(decode_u64(&buf[..8]), decode_u64(&buf[8..])),
+            None => Revision::default(),
+        };
+        let map = match engine.get(MAP_KEY) {
+            Some(buf) => ClusterMap::decode(&buf).expect("corrupted cluster map"),
+            None => ClusterMap::new_initial(),
+        };
+        StateMachine {
+            engine,
+            applied,
+            pending: BTreeMap::new(),
+            pending_events: Vec::new(),
+            events: Vec::new(),
+            map: Arc::new(map),
+            pending_map: None,
+        }
+    }
+
+    /// Revision of the last applied command.
+    pub fn applied(&self) -> Revision {
+        self.applied
+    }
+
+    /// Cluster map after the last applied command.
+    pub fn map(&self) -> Arc<ClusterMap> {
+        self.map.clone()
+    }
+
+    /// Apply command committed at `revision`.
+    ///
+    /// Commands at or before applied revision are replayed after restart and skipped,
+    /// return None for them.
+    pub fn apply(
+        &mut self,
+        revision: Revision,
+        cmd: &Command,
+    ) -> Result<Option<CommandResult>, Error> {
+        if revision.index <= self.applied.index {
+            return Ok(None);
+        }
+        let index = revision.index;
+        let res = match cmd {
+            Command::AllocOid(cnt) => self.alloc_oid(*cnt),
+            Command::KvPut(key, value) => {
+                Ok(CommandResult::Modified(self.kv_put(key, value, index)))
+            }
+            Command::KvDelete(key) => Ok(CommandResult::Deleted(self.kv_delete(key, index))),
+            Command::KvCas(key, expected, value) => {
+                let compare = Compare::RevisionEquals(key.clone(), *expected);
+                let put = TxnOp::Put(key.clone(), value.clone());
+                self.txn(&[compare], &[put], &[], index)
+                    .map(|_| CommandResult::Modified(index))
+            }
+            Command::Txn(compares, success, failure) => self
+                .txn(compares, success, failure, index)
+                .map(CommandResult::Txn),
+            Command::LeaseGrant(ttl) => self.lease_grant(*ttl, index),
+            Command::LeaseRevoke(id) => self.lease_revoke(*id, index),
+            Command::KvPutLease(key, value, id) => self
+                .kv_put_lease(key, value, *id, index)
+                .map(CommandResult::Modified),
+            Command::ChangeMap(log) => self.change_map(log, revision),
+            Command::OcCreate(kind, size) => self
+                .oc_update(*kind, |table| {
+                    table.create(*size).ok_or_else(|| kind.full_error())
+                })
+                .map(CommandResult::OcCreated),
+            Command::OcDeprecate(kind, id) => self
+                .oc_update(*kind, |table| table.deprecate(*id))
+                .map(|_| CommandResult::OcChanged),
+            Command::OcDelete(kind, id) => self
+                .oc_update(*kind, |table| table.delete(*id))
+                .map(|_| CommandResult::OcChanged),
+            Command::OcAcquire(stripe, chunk) => self.oc_acquire(*stripe, *chunk),
+            Command::OcRelease(stripe, chunk) => self.oc_release(*stripe, *chunk),
+            Command::OcCreateClass(class) => self.oc_create_class(class),
+        };
+        // failed commands are applied too, they change nothing
+        if res.is_err() {
+            self.pending.clear();
+            self.pending_events.clear();
+            self.pending_map = None;
+        }
+        let mut buf = encode_u64(revision.index).to_vec();
+        buf.extend_from_slice(&encode_u64(revision.term));
+        self.write(APPLIED_KEY, Some(buf));
+        let batch: Vec<_> = std::mem::take(&mut self.pending).into_iter().collect();
+        if let Err(e) = self.engine.write_batch(&batch) {
+            self.pending_events.clear();
+            self.pending_map = None;
+            return Err(e.into());
+        }
+        self.applied = revision;
+        self.events.append(&mut self.pending_events);
+        if let Some(map) = self.pending_map.take() {
+            self.map = map;
+        }
+        res.map(Some)
+    }
+
+    /// Read key, including pending writes of the applying command.
+    fn read(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.pending.get(key) {
+            Some(value) => value.clone(),
+            None => self.engine.get(key),
+        }
+    }
+
+    fn write(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+        self.pending.insert(key.to_vec(), value);
+    }
+
+    fn change_map(&mut self, log: &ChangeLog, revision: Revision) -> Result<CommandResult, Error> {
+        let map = self.map.apply_change(log, Some(revision))?;
+        self.write(MAP_KEY, Some(map.encode()));
+        self.pending_map = Some(Arc::new(map));
+        Ok(CommandResult::MapChanged(log.version))
+    }
+
+    fn alloc_oid(&mut self, cnt: u64) -> Result<CommandResult, Error> {
+        if cnt == 0 {
+            return Err(Error::InvalidArg);
+        }
+        let start = self.read(NEXT_OID_KEY).map_or(0, |buf| decode_u64(&buf));
+        let end = start.checked_add(cnt).ok_or(Error::OidExhausted)?;
+        self.write(NEXT_OID_KEY, Some(encode_u64(end).to_vec()));
+        Ok(CommandResult::OidRange(start, end))
+    }
+
+    /// Get chunk or stripe types table.
+    ///
+    /// Read from local state, may be stale on followers.
+    pub fn oc_table(&self, kind: OcKind) -> OcTable {
+        match self.read(oc_key(kind)) {
+            Some(buf) => OcTable::decode(&buf).expect("corrupted oc table"),
+            None => OcTable::default(),
+        }
+    }
+
+    fn oc_update<T>(
+        &mut self,
+        kind: OcKind,
+        update: impl FnOnce(&mut OcTable) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut table = self.oc_table(kind);
+        let res = update(&mut table)?;
+        self.write(oc_key(kind), Some(table.encode()));
+        Ok(res)
+    }
+
+    fn oc_acquire(&mut self, stripe: u8, chunk: u8) -> Result<CommandResult, Error> {
+        let stripe_cnt = self.oc_update(OcKind::Stripe, |table| table.acquire(stripe))?;
+        let chunk_size = self.oc_update(OcKind::Chunk, |table| table.acquire(chunk))?;
+        Ok(CommandResult::OcAcquired(stripe_cnt, chunk_size))
+    }
+
+    fn oc_release(&mut self, stripe: u8, chunk: u8) -> Result<CommandResult, Error> {
+        self.oc_update(OcKind::Stripe, |table| table.release(stripe))?;
+        self.oc_update(OcKind::Chunk, |table| table.release(chunk))?;
+        Ok(CommandResult::OcChanged)
+    }
+
+    /// Get object class `name`.
+    ///
+    /// Read from local state, may be stale on followers.
+    pub fn oc_class(&self, name: &str) -> Option<ObjectClass> {
+        let buf = self.read(&oc_class_key(name))?;
+        Some(ObjectClass::decode(&buf).expect("corrupted object class"))
+    }
+
+    /// Validate `class` against current map and put it under `OC_CLASS_PREFIX`.
+    fn oc_create_class(&mut self, class: &ObjectClass) -> Result<CommandResult, Error> {
+        class.validate(&self.map)?;
+        if self.oc_class(&class.name).is_some() {
+            return Err(Error::OcClassExists);
+        }
+        self.write(&oc_class_key(&class.name), Some(class.encode()));
+        Ok(CommandResult::OcChanged)
+    }
+
+    /// Get conf key and its mod revision, the raft index it is last modified at.
+    ///
+    /// Read from local state, may be stale on followers.
+    pub fn kv_get(&self, key: &[u8]) -> Option<(Vec<u8>, u64)> {
+        let buf = self.read(&kv_key(key))?;
+        Some((buf[8..].to_vec(), decode_u64(&buf[..8])))
+    }
+
+    /// Scan conf keys with `prefix` from `start` in key order, at most `limit` keys.
+    ///
+    /// Return keys, values and mod revisions. Read from local state, may be stale on followers.
+    pub fn kv_scan(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<KvItem, Vec<u8>>, Error> {
+        let start = kv_key(start.max(prefix));
+        let end = prefix_end(&kv_key(prefix));
+        let mut items = self.engine.scan(&start, &end, limit.saturating_add(1))?;
+        let next = if items.len() > limit {
+            items.pop().map(|(key, _)| key[KV_PREFIX.len()..].to_vec())
+        } else {
+            None
+        };
+        let items = items
+            .into_iter()
+            .map(|(key, buf)| {
+                let revision = decode_u64(&buf[..8]);
+                (key[KV_PREFIX.len()..].to_vec(), buf[8..].to_vec(), revision)
+            })
+            .collect();
+        Ok(KvPage { items, next })
+    }
+
+    /// Scan conf keys with `prefix` from `start` in key order, at most `limit` keys.
+    ///
+    /// Same as `kv_scan` without values, engine still iterates over them but doesn't copy them.
+    pub fn kv_scan_keys(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<Vec<u8>, Vec<u8>>, Error> {
+        let start = kv_key(start.max(prefix));
+        let end = prefix_end(&kv_key(prefix));
+        let mut keys = self.engine.scan_keys(&start, &end, limit.saturating_add(1))?;
+        let next = if keys.len() > limit { keys.pop() } else { None };
+        let strip = |key: Vec<u8>| key[KV_PREFIX.len()..].to_vec();
+        Ok(KvPage {
+            items: keys.into_iter().map(strip).collect(),
+            next: next.map(strip),
+        })
+    }
+
+    /// Count conf keys with `prefix`.
+    pub fn kv_count(&self, prefix: &[u8]) -> Result<u64, Error> {
+        const BATCH: usize = 1024;
+        let end = prefix_end(&kv_key(prefix));
+        let mut start = kv_key(prefix);
+        let mut cnt = 0;
+        loop {
+            let keys = self.engine.scan_keys(&start, &end, BATCH)?;
+            cnt += keys.len() as u64;
+            if keys.len() < BATCH {
+                return Ok(cnt);
+            }
+            // continue after the last key
+            start = keys.into_iter().last().unwrap();
+            start.push(0);
+        }
+    }
+
+    fn kv_put(&mut self, key: &[u8], value: &[u8], index: u64) -> u64 {
+        self.detach(key);
+        let mut buf = encode_u64(index).to_vec();
+        buf.extend_from_slice(value);
+        self.write(&kv_key(key), Some(buf));
+        self.pending_events
+            .push(KvEvent::Put(key.to_vec(), value.to_vec(), index));
+        index
+    }
+
+    fn kv_delete(&mut self, key: &[u8], index: u64) -> bool {
+        let existed = self.kv_get(key).is_some();
+        if existed {
+            self.detach(key);
+            self.write(&kv_key(key), None);
+            self.pending_events
+                .push(KvEvent::Delete(key.to_vec(), index));
+        }
+        existed
+    }
+
+    fn compare(&self, compare: &Compare) -> bool {
+        match compare {
+            Compare::Exists(key) => self.kv_get(key).is_some(),
+            Compare::ValueEquals(key, expected) => {
+                matches!(self.kv_get(key), Some((value, _)) if &value == expected)
+            }
+            Compare::RevisionEquals(key, expected) => {
+                self.kv_get(key).map_or(0, |(_, revision)| revision) == *expected
+            }
+        }
+    }
+
+    /// Return `Error::TxnConflict` if compares fail and there are no failure ops.
+    fn txn(
+        &mut self,
+        compares: &[Compare],
+        success: &[TxnOp],
+        failure: &[TxnOp],
+        index: u64,
+    ) -> Result<TxnResponse, Error> {
+        let succeeded = compares.iter().all(|compare| self.compare(compare));
+        if !succeeded && failure.is_empty() {
+            return Err(Error::TxnConflict);
+        }
+        let ops = if succeeded { success } else { failure };
+        let results = ops
+            .iter()
+            .map(|op| match op {
+                TxnOp::Get(key) => Ok(TxnOpResult::Get(self.kv_get(key))),
+                TxnOp::Put(key, value) => Ok(TxnOpResult::Put(self.kv_put(key, value, index))),
+                TxnOp::PutLease(key, value, id) => self
+                    .kv_put_lease(key, value, *id, index)
+                    .map(TxnOpResult::Put),
+                TxnOp::Delete(key) => Ok(TxnOpResult::Delete(self.kv_delete(key, index))),
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(TxnResponse { succeeded, results })
+    }
+
+    /// Get ttl of lease `id`, None if revoked or expired.
+    pub fn lease_ttl(&self, id: LeaseId) -> Option<Duration> {
+        let buf = self.read(&lease_key(id))?;
+        Some(Duration::from_millis(decode_u64(&buf)))
+    }
+
+    /// All granted leases and their ttl, checked for expiry by leader.
+    pub fn leases(&self) -> Result<Vec<(LeaseId, Duration)>, Error> {
+        let end = prefix_end(LEASE_PREFIX);
+        let leases = self
+            .engine
+            .scan(LEASE_PREFIX, &end, usize::MAX)?
+            .into_iter()
+            .map(|(key, buf)| {
+                let id = decode_u64(&key[LEASE_PREFIX.len()..]);
+                (id, Duration::from_millis(decode_u64(&buf)))
+            })
+            .collect();
+        Ok(leases)
+    }
+
+    fn lease_grant(&mut self, ttl: Duration, index: u64) -> Result<CommandResult, Error> {
+        if ttl.is_zero() {
+            return Err(Error::InvalidArg);
+        }
+        let ttl = u64::try_from(ttl.as_millis()).map_err(|_| Error::InvalidArg)?;
+        self.write(&lease_key(index), Some(encode_u64(ttl).to_vec()));
+        Ok(CommandResult::Granted(index))
+    }
+
+    fn lease_revoke(&mut self, id: LeaseId, index: u64) -> Result<CommandResult, Error> {
+        if self.lease_ttl(id).is_none() {
+            return Err(Error::LeaseNotFound(id));
+        }
+        let prefix = lease_keys_key(id, b"");
+        let end = prefix_end(&prefix);
+        let keys = self.engine.scan(&prefix, &end, usize::MAX)?;
+        for (key, _) in &keys {
+            self.kv_delete(&key[prefix.len()..], index);
+        }
+        self.write(&lease_key(id), None);
+        Ok(CommandResult::Revoked(keys.len()))
+    }
+
+    fn kv_put_lease(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        id: LeaseId,
+        index: u64,
+    ) -> Result<u64, Error> {
+        if self.lease_ttl(id).is_none() {
+            return Err(Error::LeaseNotFound(id));
+        }
+        let revision = self.kv_put(key, value, index);
+        self.write(&lease_keys_key(id, key), Some(Vec::new()));
+        self.write(&key_lease_key(key), Some(encode_u64(id).to_vec()));
+        Ok(revision)
+    }
+
+    /// Detach `key` from its lease, if any.
+    fn detach(&mut self, key: &[u8]) {
+        let buf = match self.read(&key_lease_key(key)) {
+            Some(buf) => buf,
+            None => return,
+        };
+        self.write(&lease_keys_key(decode_u64(&buf), key), None);
+        self.write(&key_lease_key(key), None);
+    }
+
+    /// Take changes of conf keys since last call, publish them to `WatchHub`.
+    pub fn take_events(&mut self) -> Vec<KvEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+fn oc_key(kind: OcKind) -> &'static [u8] {
+    match kind {
+        OcKind::Chunk => OC_CHUNK_KEY,
+        OcKind::Stripe => OC_STRIPE_KEY,
+    }
+}
+
+fn oc_class_key(name: &str) -> Vec<u8> {
+    [OC_CLASS_PREFIX, name.as_bytes()].concat()
+}
+
+fn kv_key(key: &[u8]) -> Vec<u8> {
+    [KV_PREFIX, key].concat()
+}
+
+fn lease_key(id: LeaseId) -> Vec<u8> {
+    [LEASE_PREFIX, &encode_u64(id)].concat()
+}
+
+fn lease_keys_key(id: LeaseId, key: &[u8]) -> Vec<u8> {
+    [LEASE_KEYS_PREFIX, &encode_u64(id), key].concat()
+}
+
+fn key_lease_key(key: &[u8]) -> Vec<u8> {
+    [KEY_LEASE_PREFIX, key].concat()
+}
+
+/// Smallest key greater than all keys with `prefix`, `prefix` must not be all 0xff.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_mod::BasicEngine;
+    use crate::{TargetChangeOp, TargetLocation};
+    use rocksdb::DB;
+    use tempfile::Builder;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_alloc_oid() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine.clone());
+        let alloc = |sm: &mut StateMachine<_>, index, cnt| {
+            sm.apply(Revision::new(index, 1), &Command::AllocOid(cnt))
+        };
+        assert_eq!(
+            alloc(&mut sm, 1, 10).unwrap(),
+            Some(CommandResult::OidRange(0, 10))
+        );
+        assert_eq!(
+            alloc(&mut sm, 2, 5).unwrap(),
+            Some(CommandResult::OidRange(10, 15))
+        );
+        assert!(matches!(alloc(&mut sm, 3, 0), Err(Error::InvalidArg)));
+
+        // restart and replay
+        let mut sm = StateMachine::new(engine);
+        assert_eq!(sm.applied(), Revision::new(3, 1));
+        assert_eq!(alloc(&mut sm, 2, 5).unwrap(), None);
+        assert_eq!(
+            alloc(&mut sm, 4, 1).unwrap(),
+            Some(CommandResult::OidRange(15, 16))
+        );
+        assert!(matches!(
+            alloc(&mut sm, 5, u64::MAX),
+            Err(Error::OidExhausted)
+        ));
+        assert_eq!(
+            alloc(&mut sm, 6, u64::MAX - 16).unwrap(),
+            Some(CommandResult::OidRange(16, u64::MAX))
+        );
+
+        let cmd = Command::AllocOid(7);
+        assert_eq!(Command::decode(&cmd.encode()).unwrap(), cmd);
+    }
+
+    #[test]
+    fn test_kv_cas() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine);
+        let (key, v1, v2) = (b"key".to_vec(), b"v1".to_vec(), b"v2".to_vec());
+        let put = Command::KvPut(key.clone(), v1.clone());
+        assert_eq!(
+            sm.apply(Revision::new(1, 1), &put).unwrap(),
+            Some(CommandResult::Modified(1))
+        );
+        assert_eq!(sm.kv_get(&key), Some((v1.clone(), 1)));
+
+        // stale revision
+        let cas = Command::KvCas(key.clone(), 0, v2.clone());
+        assert!(matches!(
+            sm.apply(Revision::new(2, 1), &cas),
+            Err(Error::TxnConflict)
+        ));
+        assert_eq!(sm.kv_get(&key), Some((v1, 1)));
+        let cas = Command::KvCas(key.clone(), 1, v2.clone());
+        assert_eq!(
+            sm.apply(Revision::new(3, 1), &cas).unwrap(),
+            Some(CommandResult::Modified(3))
+        );
+        assert_eq!(sm.kv_get(&key), Some((v2.clone(), 3)));
+
+        let delete = Command::KvDelete(key.clone());
+        assert_eq!(
+            sm.apply(Revision::new(4, 2), &delete).unwrap(),
+            Some(CommandResult::Deleted(true))
+        );
+        assert_eq!(
+            sm.apply(Revision::new(5, 2), &delete).unwrap(),
+            Some(CommandResult::Deleted(false))
+        );
+        assert_eq!(sm.kv_get(&key), None);
+        assert_eq!(
+            sm.take_events(),
+            vec![
+                KvEvent::Put(key.clone(), b"v1".to_vec(), 1),
+                KvEvent::Put(key.clone(), v2.clone(), 3),
+                KvEvent::Delete(key.clone(), 4),
+            ]
+        );
+        // create if not exists
+        let cas = Command::KvCas(key.clone(), 0, v2.clone());
+        assert_eq!(
+            sm.apply(Revision::new(6, 2), &cas).unwrap(),
+            Some(CommandResult::Modified(6))
+        );
+        assert_eq!(sm.kv_get(&key), Some((v2, 6)));
+    }
+
+    #[test]
+    fn test_txn() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine);
+        let (chunk, stripe) = (b"oc/chunk/1".to_vec(), b"oc/stripe/1".to_vec());
+        let txn = |compares, success, failure| Command::Txn(compares, success, failure);
+
+        let create = txn(
+            vec![
+                Compare::RevisionEquals(chunk.clone(), 0),
+                Compare::RevisionEquals(stripe.clone(), 0),
+            ],
+            vec![
+                TxnOp::Put(chunk.clone(), b"4096".to_vec()),
+                TxnOp::Put(stripe.clone(), b"8".to_vec()),
+                TxnOp::Get(chunk.clone()),
+            ],
+            vec![],
+        );
+        let expect = TxnResponse {
+            succeeded: true,
+            results: vec![
+                TxnOpResult::Put(1),
+                TxnOpResult::Put(1),
+                TxnOpResult::Get(Some((b"4096".to_vec(), 1))),
+            ],
+        };
+        assert_eq!(
+            sm.apply(Revision::new(1, 1), &create).unwrap(),
+            Some(CommandResult::Txn(expect))
+        );
+
+        // create again, guards fail without failure ops, nothing changed
+        assert!(matches!(
+            sm.apply(Revision::new(2, 1), &create),
+            Err(Error::TxnConflict)
+        ));
+        assert_eq!(sm.applied(), Revision::new(2, 1));
+        assert_eq!(sm.take_events().len(), 2);
+
+        let fallback = txn(
+            vec![Compare::Exists(b"missing".to_vec())],
+            vec![],
+            vec![TxnOp::Delete(stripe.clone()), TxnOp::Get(stripe.clone())],
+        );
+        let expect = TxnResponse {
+            succeeded: false,
+            results: vec![TxnOpResult::Delete(true), TxnOpResult::Get(None)],
+        };
+        assert_eq!(
+            sm.apply(Revision::new(3, 1), &fallback).unwrap(),
+            Some(CommandResult::Txn(expect))
+        );
+        // guards hold, success ops run
+        let update = txn(
+            vec![Compare::ValueEquals(chunk.clone(), b"4096".to_vec())],
+            vec![TxnOp::Delete(chunk.clone())],
+            vec![],
+        );
+        let res = sm.apply(Revision::new(4, 1), &update).unwrap();
+        assert!(matches!(res, Some(CommandResult::Txn(res)) if res.succeeded));
+        assert_eq!(sm.kv_get(&chunk), None);
+        assert_eq!(sm.kv_get(&stripe), None);
+    }
+
+    #[test]
+    fn test_kv_scan() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine);
+        for (i, key) in ["a", "vol/1", "vol/2", "vol/3", "vom"].iter().enumerate() {
+            let put = Command::KvPut(key.as_bytes().to_vec(), b"v".to_vec());
+            sm.apply(Revision::new(i as u64 + 1, 1), &put).unwrap();
+        }
+        let keys = |page: &KvPage<KvItem, Vec<u8>>| {
+            page.items
+                .iter()
+                .map(|(key, _, _)| String::from_utf8(key.clone()).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let page = sm.kv_scan(b"vol/", b"", 2).unwrap();
+        assert_eq!(keys(&page), vec!["vol/1", "vol/2"]);
+        assert_eq!(page.items[0].2, 2);
+        assert_eq!(page.next, Some(b"vol/3".to_vec()));
+        let page = sm.kv_scan(b"vol/", &page.next.unwrap(), 2).unwrap();
+        assert_eq!(keys(&page), vec!["vol/3"]);
+        assert_eq!(page.next, None);
+        let page = sm.kv_scan(b"vol/", b"vol/2", 1).unwrap();
+        assert_eq!(keys(&page), vec!["vol/2"]);
+
+        let page = sm.kv_scan_keys(b"vol/", b"", 2).unwrap();
+        assert_eq!(page.items, vec![b"vol/1".to_vec(), b"vol/2".to_vec()]);
+        assert_eq!(page.next, Some(b"vol/3".to_vec()));
+        let page = sm.kv_scan_keys(b"vol/", b"vol/3", 2).unwrap();
+        assert_eq!(page.items, vec![b"vol/3".to_vec()]);
+        assert_eq!(page.next, None);
+
+        assert_eq!(sm.kv_count(b"vol/").unwrap(), 3);
+        assert_eq!(sm.kv_count(b"").unwrap(), 5);
+        assert_eq!(sm.kv_count(b"x").unwrap(), 0);
+        assert_eq!(prefix_end(b"a\xff\xff"), b"b".to_vec());
+    }
+
+    #[test]
+    fn test_lease() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine);
+        let ttl = Duration::from_secs(10);
+        assert_eq!(
+            sm.apply(Revision::new(1, 1), &Command::LeaseGrant(ttl))
+                .unwrap(),
+            Some(CommandResult::Granted(1))
+        );
+        assert_eq!(sm.lease_ttl(1), Some(ttl));
+        assert_eq!(sm.leases().unwrap(), vec![(1, ttl)]);
+        assert!(matches!(
+            sm.apply(Revision::new(2, 1), &Command::LeaseGrant(Duration::ZERO)),
+            Err(Error::InvalidArg)
+        ));
+
+        let (a, b, c) = (
+            b"mount/a".to_vec(),
+            b"mount/b".to_vec(),
+            b"mount/c".to_vec(),
+        );
+        for (i, key) in [&a, &b, &c].into_iter().enumerate() {
+            let put = Command::KvPutLease(key.clone(), b"client".to_vec(), 1);
+            sm.apply(Revision::new(i as u64 + 3, 1), &put).unwrap();
+        }
+        assert!(matches!(
+            sm.apply(
+                Revision::new(6, 1),
+                &Command::KvPutLease(a.clone(), vec![], 42)
+            ),
+            Err(Error::LeaseNotFound(42))
+        ));
+        // plain put detaches key from lease
+        sm.apply(
+            Revision::new(7, 1),
+            &Command::KvPut(b.clone(), b"v".to_vec()),
+        )
+        .unwrap();
+        sm.apply(Revision::new(8, 1), &Command::KvDelete(c.clone()))
+            .unwrap();
+        sm.take_events();
+
+        assert_eq!(
+            sm.apply(Revision::new(9, 1), &Command::LeaseRevoke(1))
+                .unwrap(),
+            Some(CommandResult::Revoked(1))
+        );
+        assert_eq!(sm.kv_get(&a), None);
+        assert_eq!(sm.kv_get(&b), Some((b"v".to_vec(), 7)));
+        assert_eq!(sm.take_events(), vec![KvEvent::Delete(a, 9)]);
+        assert_eq!(sm.lease_ttl(1), None);
+        assert!(sm.leases().unwrap().is_empty());
+        assert!(matches!(
+            sm.apply(Revision::new(10, 1), &Command::LeaseRevoke(1)),
+            Err(Error::LeaseNotFound(1))
+        ));
+
+        // create key attached to lease only if absent, as a lock
+        sm.apply(Revision::new(11, 1), &Command::LeaseGrant(ttl))
+            .unwrap();
+        let lock = b"lock/gc".to_vec();
+        let acquire = |id| {
+            Command::Txn(
+                vec![Compare::RevisionEquals(lock.clone(), 0)],
+                vec![TxnOp::PutLease(lock.clone(), b"me".to_vec(), id)],
+                vec![TxnOp::Get(lock.clone())],
+            )
+        };
+        assert!(matches!(
+            sm.apply(Revision::new(12, 1), &acquire(1)),
+            Err(Error::LeaseNotFound(1))
+        ));
+        assert_eq!(sm.kv_get(&lock), None);
+        let res = sm.apply(Revision::new(13, 1), &acquire(11)).unwrap();
+        let acquired = TxnResponse {
+            succeeded: true,
+            results: vec![TxnOpResult::Put(13)],
+        };
+        assert_eq!(res, Some(CommandResult::Txn(acquired)));
+        let res = sm.apply(Revision::new(14, 1), &acquire(11)).unwrap();
+        assert!(matches!(res, Some(CommandResult::Txn(res)) if !res.succeeded));
+        assert_eq!(
+            sm.apply(Revision::new(15, 1), &Command::LeaseRevoke(11))
+                .unwrap(),
+            Some(CommandResult::Revoked(1))
+        );
+        assert_eq!(sm.kv_get(&lock), None);
+    }
+
+    #[test]
+    fn test_change_map() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine.clone());
+        let uuid = Uuid::new_v4();
+        let location = TargetLocation::with_host("host", "dev").unwrap();
+        let create = TargetChangeOp::Create(0, None, location, Default::default());
+        let log = sm.map().change_builder().add(uuid, create).build().unwrap();
+        assert_eq!(
+            sm.apply(Revision::new(1, 1), &Command::ChangeMap(log.clone()))
+                .unwrap(),
+            Some(CommandResult::MapChanged(log.version))
+        );
+        assert_eq!(sm.map().revision, Revision::new(1, 1));
+        assert!(matches!(
+            sm.apply(Revision::new(2, 1), &Command::ChangeMap(log)),
+            Err(Error::InvalidChangeLog(_))
+        ));
+        let map = sm.map();
+        assert_eq!(map.get_target_by_uuid(&uuid).unwrap().get_id(), Some(0));
+
+        // restart
+        let sm = StateMachine::new(engine);
+        assert_eq!(sm.map(), map);
+    }
+
+    #[test]
+    fn test_oc_types() {
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let engine = BasicEngine::from_db(Arc::new(DB::open_default(path).unwrap()));
+        let mut sm = StateMachine::new(engine.clone());
+        let mut index = 0;
+        let mut apply = |sm: &mut StateMachine<_>, cmd| {
+            index += 1;
+            sm.apply(Revision::new(index, 1), &cmd)
+        };
+        for i in 0..2 {
+            assert_eq!(
+                apply(&mut sm, Command::OcCreate(OcKind::Stripe, 4 + i)).unwrap(),
+                Some(CommandResult::OcCreated(i as u8))
+            );
+        }
+        assert_eq!(
+            apply(&mut sm, Command::OcCreate(OcKind::Chunk, 4096)).unwrap(),
+            Some(CommandResult::OcCreated(0))
+        );
+        assert_eq!(
+            apply(&mut sm, Command::OcAcquire(1, 0)).unwrap(),
+            Some(CommandResult::OcAcquired(5, 4096))
+        );
+
+        // acquire is atomic, stripe type is not counted if chunk type fails
+        apply(&mut sm, Command::OcDeprecate(OcKind::Chunk, 0)).unwrap();
+        assert!(matches!(
+            apply(&mut sm, Command::OcAcquire(0, 0)),
+            Err(Error::OcTypeDeprecated(0))
+        ));
+        assert_eq!(sm.oc_table(OcKind::Stripe).get(0).unwrap().refs, 0);
+        assert!(matches!(
+            apply(&mut sm, Command::OcDelete(OcKind::Chunk, 0)),
+            Err(Error::OcTypeInUse(0, 1))
+        ));
+
+        // restart
+        let mut sm = StateMachine::new(engine);
+        assert_eq!(sm.oc_table(OcKind::Stripe).get(1).unwrap().refs, 1);
+        apply(&mut sm, Command::OcRelease(1, 0)).unwrap();
+        assert_eq!(
+            apply(&mut sm, Command::OcDelete(OcKind::Chunk, 0)).unwrap(),
+            Some(CommandResult::OcChanged)
+        );
+        assert_eq!(sm.oc_table(OcKind::Chunk), OcTable::default());
+        assert_eq!(
+            apply(&mut sm, Command::OcCreate(OcKind::Chunk, 8192)).unwrap(),
+            Some(CommandResult::OcCreated(0))
+        );
+    }
+}
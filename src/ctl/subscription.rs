@@ -0,0 +1,381 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use madsim::net::NetLocalHandle;
+use madsim::Request;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use crate::{ChangeLog, ClusterMap, ClusterMapVersion, Error, Revision};
+
+/// Long poll request for cluster map updates.
+///
+/// Server replies once it has a map newer than `version`, or an empty
+/// `MapUpdate::Deltas` after its poll timeout, clients should poll again.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("MapUpdate")]
+pub struct SubscribeMap {
+    /// Version client has, None to ask for full map.
+    pub version: Option<ClusterMapVersion>,
+}
+
+/// Cluster map update sent to subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MapUpdate {
+    /// Changelogs after client version, `(logs, revision of newest map)`
+    Deltas(Vec<ChangeLog>, Revision),
+    /// Client is behind retained changelogs, `(full map)`
+    Full(ClusterMap),
+}
+
+struct PublisherState {
+    map: Arc<ClusterMap>,
+    /// Version of the map before the oldest retained changelog.
+    base: ClusterMapVersion,
+    logs: VecDeque<ChangeLog>,
+}
+
+/// Publish cluster map updates to subscribers.
+///
+/// Every controller node, leader or follower, feeds its publisher with changelogs
+/// as its `Replica` applies them from raft log, so clients can subscribe to any node
+/// and the leader doesn't serve all clients alone.
+///
+/// Only the latest `window` changelogs are retained, clients behind that get full map.
+pub struct MapPublisher {
+    window: usize,
+    state: RwLock<PublisherState>,
+    version_tx: watch::Sender<ClusterMapVersion>,
+    version_rx: watch::Receiver<ClusterMapVersion>,
+}
+
+impl MapPublisher {
+    pub fn new(map: Arc<ClusterMap>, window: usize) -> MapPublisher {
+        let (version_tx, version_rx) = watch::channel(map.version);
+        MapPublisher {
+            window,
+            state: RwLock::new(PublisherState {
+                base: map.version,
+                map,
+                logs: VecDeque::new(),
+            }),
+            version_tx,
+            version_rx,
+        }
+    }
+
+    /// Publish `map` generated by applying `log` to current map.
+    pub fn publish(&self, log: ChangeLog, map: Arc<ClusterMap>) {
+        debug_assert_eq!(log.version, map.version);
+        let version = map.version;
+        {
+            let mut state = self.state.write().unwrap();
+            if state.map.version.is_next(&version) {
+                state.logs.push_back(log);
+            } else {
+                // we missed some changelogs, eg: installed a snapshot
+                state.logs.clear();
+                state.base = version;
+            }
+            state.map = map;
+            while state.logs.len() > self.window {
+                state.base = state.logs.pop_front().unwrap().version;
+            }
+        }
+        let _ = self.version_tx.send(version);
+    }
+
+    /// Get current cluster map.
+    pub fn current_map(&self) -> Arc<ClusterMap> {
+        self.state.read().unwrap().map.clone()
+    }
+
+    /// Get updates since `version`, None if `version` is up to date.
+    ///
+    /// Also None if `version` is ahead of our map, eg: we are a lagging follower
+    /// and client got its map from another node, it waits until we catch up.
+    pub fn updates_since(&self, version: Option<ClusterMapVersion>) -> Option<MapUpdate> {
+        let state = self.state.read().unwrap();
+        let version = match version {
+            Some(version) if version >= state.map.version => return None,
+            Some(version) => version,
+            None => return Some(MapUpdate::Full(state.map.as_ref().clone())),
+        };
+        let start = if version == state.base {
+            Some(0)
+        } else {
+            state
+                .logs
+                .iter()
+                .position(|log| log.version == version)
+                .map(|i| i + 1)
+        };
+        Some(match start {
+            Some(start) => {
+                let logs = state.logs.iter().skip(start).cloned().collect();
+                MapUpdate::Deltas(logs, state.map.revision)
+            }
+            // too old or unknown version
+            None => MapUpdate::Full(state.map.as_ref().clone()),
+        })
+    }
+
+    /// Wait updates since `version`, return empty deltas after `timeout`.
+    pub async fn wait_update(
+        &self,
+        version: Option<ClusterMapVersion>,
+        timeout: Duration,
+    ) -> MapUpdate {
+        let deadline = madsim::time::Instant::now() + timeout;
+        let mut version_rx = self.version_rx.clone();
+        loop {
+            if let Some(update) = self.updates_since(version) {
+                return update;
+            }
+            let remain = deadline.saturating_duration_since(madsim::time::Instant::now());
+            let changed = madsim::time::timeout(remain, version_rx.changed()).await;
+            if changed.is_err() {
+                return MapUpdate::Deltas(Vec::new(), self.current_map().revision);
+            }
+        }
+    }
+
+    /// Wait until a map newer than `version` is published.
+    pub async fn wait_map(&self, version: ClusterMapVersion) -> Arc<ClusterMap> {
+        let mut version_rx = self.version_rx.clone();
+        loop {
+            let map = self.current_map();
+            if map.version > version {
+                return map;
+            }
+            // sender is owned by self, never closed
+            let _ = version_rx.changed().await;
+        }
+    }
+
+    /// Serve `SubscribeMap` on local network endpoint.
+    pub fn serve(self: &Arc<Self>, poll_timeout: Duration) {
+        let publisher = self.clone();
+        NetLocalHandle::current().add_rpc_handler(move |req: SubscribeMap| {
+            let publisher = publisher.clone();
+            async move { publisher.wait_update(req.version, poll_timeout).await }
+        });
+    }
+}
+
+/// Subscribe cluster map updates from controller nodes.
+///
+/// Servers are tried in turn, switching to the next one on network error.
+pub struct MapSubscriber {
+    servers: Vec<SocketAddr>,
+    next: AtomicUsize,
+    /// Must be longer than poll timeout of servers.
+    timeout: Duration,
+}
+
+impl MapSubscriber {
+    pub fn new(servers: Vec<SocketAddr>, timeout: Duration) -> MapSubscriber {
+        assert!(!servers.is_empty());
+        MapSubscriber {
+            servers,
+            next: AtomicUsize::new(0),
+            timeout,
+        }
+    }
+
+    /// Wait next update of `map` from one server, return `map` itself if nothing changed.
+    ///
+    /// If deltas can't be applied, eg: server reset its history, fetch full map instead.
+    pub async fn poll(&self, map: &Arc<ClusterMap>) -> Result<Arc<ClusterMap>, Error> {
+        let update = self.call(Some(map.version)).await?;
+        match Self::apply(map, update) {
+            Ok(map) => Ok(map),
+            Err(Error::InvalidChangeLog(_)) => {
+                let update = self.call(None).await?;
+                Self::apply(map, update)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn call(&self, version: Option<ClusterMapVersion>) -> Result<MapUpdate, Error> {
+        let i = self.next.load(Ordering::Relaxed);
+        let server = self.servers[i % self.servers.len()];
+        let res = NetLocalHandle::current()
+            .call_timeout(server, SubscribeMap { version }, self.timeout)
+            .await;
+        if res.is_err() {
+            let _ = self
+                .next
+                .compare_exchange(i, i + 1, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        Ok(res?)
+    }
+
+    fn apply(map: &Arc<ClusterMap>, update: MapUpdate) -> Result<Arc<ClusterMap>, Error> {
+        match update {
+            MapUpdate::Deltas(logs, revision) => match map.apply_all(&logs, Some(revision))? {
+                Some(new_map) => Ok(Arc::new(new_map)),
+                None => Ok(map.clone()),
+            },
+            MapUpdate::Full(new_map) if new_map.version > map.version => Ok(Arc::new(new_map)),
+            MapUpdate::Full(_) => Ok(map.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctl::replica::tests::local_replica;
+    use crate::{TargetChangeOp, TargetLocation};
+    use uuid::Uuid;
+
+    /// Initial map and `n` maps after it, each creates a target, with changelogs to them.
+    fn build_maps(n: u32) -> (Vec<Arc<ClusterMap>>, Vec<ChangeLog>) {
+        let mut maps = vec![Arc::new(ClusterMap::new_initial())];
+        let mut logs = Vec::new();
+        for i in 0..n {
+            let map = maps.last().unwrap();
+            let location = TargetLocation::with_host("host", "dev").unwrap();
+            let create = TargetChangeOp::Create(i, None, location, Default::default());
+            let log = map
+                .change_builder()
+                .add(Uuid::from_u128(i as u128), create)
+                .build()
+                .unwrap();
+            let revision = Revision::new(i as u64 + 1, 1);
+            maps.push(Arc::new(map.apply_change(&log, Some(revision)).unwrap()));
+            logs.push(log);
+        }
+        (maps, logs)
+    }
+
+    #[test]
+    fn test_publisher_window() {
+        let (maps, logs) = build_maps(3);
+        let publisher = MapPublisher::new(maps[0].clone(), 2);
+        for (log, map) in logs.into_iter().zip(&maps[1..]) {
+            publisher.publish(log, map.clone());
+        }
+
+        let latest = maps.last().unwrap();
+        assert_eq!(publisher.updates_since(Some(latest.version)), None);
+        for map in &maps[1..3] {
+            let update = publisher.updates_since(Some(map.version)).unwrap();
+            assert!(matches!(update, MapUpdate::Deltas(_, revision) if revision.index == 3));
+            assert_eq!(&MapSubscriber::apply(map, update).unwrap(), latest);
+        }
+        // behind the window
+        let update = publisher.updates_since(Some(maps[0].version)).unwrap();
+        assert!(matches!(update, MapUpdate::Full(_)));
+        assert_eq!(&MapSubscriber::apply(&maps[0], update).unwrap(), latest);
+        // ahead of us, wait until we catch up
+        let ahead = latest.version.next_major();
+        assert_eq!(publisher.updates_since(Some(ahead)), None);
+    }
+
+    #[madsim::test]
+    async fn test_wait_map() {
+        let (maps, logs) = build_maps(2);
+        let publisher = MapPublisher::new(maps[0].clone(), 2);
+        let mut logs = logs.into_iter();
+        publisher.publish(logs.next().unwrap(), maps[1].clone());
+        // already newer
+        assert_eq!(publisher.wait_map(maps[0].version).await, maps[1]);
+
+        let publish = async {
+            madsim::time::sleep(Duration::from_secs(1)).await;
+            publisher.publish(logs.next().unwrap(), maps[2].clone());
+        };
+        let (_, map) = tokio::join!(publish, publisher.wait_map(maps[1].version));
+        assert_eq!(map, maps[2]);
+    }
+
+    #[test]
+    fn test_subscribe_lagging() {
+        let runtime = madsim::Runtime::new();
+        let server_addr = SocketAddr::from(([10, 0, 0, 1], 1));
+        let server = runtime.create_host(server_addr).unwrap();
+        let client = runtime.create_host(SocketAddr::from(([10, 0, 0, 2], 1))).unwrap();
+        let (maps, logs) = build_maps(3);
+
+        // follower lagging at maps[1], client already has maps[2] from another node
+        let publisher = Arc::new(MapPublisher::new(maps[1].clone(), 8));
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+        let updates: Vec<_> = logs.into_iter().zip(maps[1..].to_vec()).skip(1).collect();
+        server
+            .spawn(async move {
+                publisher.serve(Duration::from_secs(10));
+                ready_tx.send(()).unwrap();
+                for (log, map) in updates {
+                    madsim::time::sleep(Duration::from_secs(1)).await;
+                    publisher.publish(log, map);
+                }
+            })
+            .detach();
+
+        let task = client.spawn(async move {
+            ready_rx.await.unwrap();
+            let subscriber = MapSubscriber::new(vec![server_addr], Duration::from_secs(20));
+            let start = madsim::time::Instant::now();
+            let map = subscriber.poll(&maps[2]).await.unwrap();
+            // no full map of the older version, deltas once follower moves past us
+            assert_eq!(map, maps[3]);
+            assert!(start.elapsed() >= Duration::from_secs(2));
+
+            // nothing new within poll timeout
+            let start = madsim::time::Instant::now();
+            assert_eq!(subscriber.poll(&map).await.unwrap(), map);
+            assert!(start.elapsed() >= Duration::from_secs(10));
+        });
+        runtime.block_on(task);
+    }
+    #[test]
+    fn test_subscribe_replica() {
+        let runtime = madsim::Runtime::new();
+        let server_addr = SocketAddr::from(([10, 0, 0, 1], 1));
+        let server = runtime.create_host(server_addr).unwrap();
+        let client = runtime
+            .create_host(SocketAddr::from(([10, 0, 0, 2], 1)))
+            .unwrap();
+
+        let (initial_tx, initial_rx) = tokio::sync::oneshot::channel();
+        let (latest_tx, latest_rx) = tokio::sync::oneshot::channel();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        server
+            .spawn(async move {
+                let (replica, _path) = local_replica();
+                replica.publisher().serve(Duration::from_secs(10));
+                initial_tx.send(replica.current_map()).unwrap();
+                // changelogs applied by replica are published without calling publish
+                for i in 0..3 {
+                    madsim::time::sleep(Duration::from_secs(1)).await;
+                    let location = TargetLocation::with_host("host", "dev").unwrap();
+                    let create = TargetChangeOp::Create(i, None, location, Default::default());
+                    let uuid = Uuid::from_u128(i as u128);
+                    let build =
+                        |map: &ClusterMap| map.change_builder().add(uuid, create.clone()).build();
+                    replica
+                        .change_map(|map| Ok(Some(build(map)?)))
+                        .await
+                        .unwrap();
+                }
+                latest_tx.send(replica.current_map()).unwrap();
+                let _ = done_rx.await;
+            })
+            .detach();
+
+        let task = client.spawn(async move {
+            let mut map = initial_rx.await.unwrap();
+            let subscriber = MapSubscriber::new(vec![server_addr], Duration::from_secs(20));
+            while map.targets.len() < 3 {
+                map = subscriber.poll(&map).await.unwrap();
+            }
+            assert_eq!(map, latest_rx.await.unwrap());
+            done_tx.send(()).unwrap();
+        });
+        runtime.block_on(task);
+    }
+}
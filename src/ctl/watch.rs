@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use crate::{Error, KvEvent, KvWatcher, Revision};
+
+struct Watch {
+    prefix: Vec<u8>,
+    tx: mpsc::UnboundedSender<KvEvent>,
+}
+
+struct HubState {
+    /// Recent events and revision they are committed at.
+    events: VecDeque<(Revision, KvEvent)>,
+    /// Revision of the newest dropped event, watches can't resume at or before it.
+    compacted: Revision,
+    watches: Vec<Watch>,
+}
+
+/// Dispatch conf key changes to watches.
+///
+/// The latest `window` events are retained, so watches can resume from
+/// a recent revision after reconnecting.
+pub struct WatchHub {
+    window: usize,
+    state: Mutex<HubState>,
+}
+
+impl WatchHub {
+    pub fn new(window: usize, applied: Revision) -> WatchHub {
+        WatchHub {
+            window,
+            state: Mutex::new(HubState {
+                events: VecDeque::new(),
+                compacted: applied,
+                watches: Vec::new(),
+            }),
+        }
+    }
+
+    /// Watch keys with `prefix`, from events at or after `from_revision`.
+    ///
+    /// `from_revision` 0 means only new events,
+    /// return `Error::RevisionCompacted` if events since `from_revision` are dropped.
+    pub fn watch(&self, prefix: &[u8], from_revision: u64) -> Result<KvWatcher, Error> {
+        let mut state = self.state.lock().unwrap();
+        if from_revision != 0 && from_revision <= state.compacted.index {
+            return Err(Error::RevisionCompacted(state.compacted));
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        if from_revision != 0 {
+            let events = state
+                .events
+                .iter()
+                .map(|(_, event)| event)
+                .filter(|event| event.revision() >= from_revision)
+                .filter(|event| event.key().starts_with(prefix));
+            for event in events {
+                let _ = tx.send(event.clone());
+            }
+        }
+        state.watches.push(Watch {
+            prefix: prefix.to_vec(),
+            tx,
+        });
+        Ok(KvWatcher::new(rx, from_revision))
+    }
+
+    /// Publish events of command applied at `revision`.
+    pub fn publish(&self, revision: Revision, events: Vec<KvEvent>) {
+        let mut state = self.state.lock().unwrap();
+        for event in events {
+            // cancelled watches are removed
+            state.watches.retain(|watch| {
+                !event.key().starts_with(&watch.prefix) || watch.tx.send(event.clone()).is_ok()
+            });
+            state.events.push_back((revision, event));
+        }
+        while state.events.len() > self.window {
+            state.compacted = state.events.pop_front().unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, revision: u64) -> KvEvent {
+        KvEvent::Put(key.as_bytes().to_vec(), b"v".to_vec(), revision)
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let hub = WatchHub::new(3, Revision::default());
+        let mut watcher = hub.watch(b"a/", 0).unwrap();
+        hub.publish(Revision::new(1, 1), vec![put("a/1", 1)]);
+        hub.publish(Revision::new(2, 1), vec![put("b/1", 2)]);
+        let delete = KvEvent::Delete(b"a/1".to_vec(), 3);
+        hub.publish(Revision::new(3, 1), vec![delete.clone()]);
+        assert_eq!(watcher.next().await, Some(put("a/1", 1)));
+        assert_eq!(watcher.next().await, Some(delete.clone()));
+        assert_eq!(watcher.next_revision(), 4);
+
+        // resume
+        let mut resumed = hub.watch(b"a/", 2).unwrap();
+        assert_eq!(resumed.next().await, Some(delete));
+        resumed.cancel();
+        hub.publish(Revision::new(4, 2), vec![put("a/2", 4)]);
+        assert_eq!(watcher.next().await, Some(put("a/2", 4)));
+        assert_eq!(hub.state.lock().unwrap().watches.len(), 1);
+
+        assert!(matches!(
+            hub.watch(b"a/", 1),
+            Err(Error::RevisionCompacted(revision)) if revision.index == 1
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    cluster::{ClusterMap, ClusterMapVersion},
+    target::{TargetCapacity, TargetId, TargetInfo, TargetLocation},
+};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// Change of a target between two cluster maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetTransition {
+    /// Target becomes UP, `(url)`
+    Up(SocketAddr),
+    /// Target becomes DOWN
+    Down,
+    /// UP target changes url, `(old url, new url)`
+    UrlChanged(SocketAddr, SocketAddr),
+    /// Target becomes IN
+    In,
+    /// Target becomes OUT
+    Out,
+    /// Target becomes DRAIN
+    Drain,
+    /// Target moved, `(old location, new location)`
+    Moved(TargetLocation, TargetLocation),
+    /// Placement weight changed, `(old weight, new weight)`
+    WeightChanged(u32, u32),
+    /// Capacity changed, `(new capacity)`
+    CapacityChanged(TargetCapacity),
+    /// Target enters or leaves maintenance
+    Maintenance(bool),
+}
+
+/// All changes of one target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetDiff {
+    pub id: TargetId,
+    pub uuid: Uuid,
+    pub transitions: Vec<TargetTransition>,
+}
+
+/// Difference between two cluster maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMapDiff {
+    /// Version of the old map.
+    pub from: ClusterMapVersion,
+    /// Version of the new map.
+    pub to: ClusterMapVersion,
+    /// Targets that only exist in the new map.
+    pub added: Vec<TargetId>,
+    /// Targets that only exist in the old map.
+    pub removed: Vec<TargetId>,
+    /// Targets that exist in both maps and changed.
+    pub changed: Vec<TargetDiff>,
+}
+
+impl ClusterMapDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Get changes of target `id`.
+    pub fn get_target(&self, id: TargetId) -> Option<&TargetDiff> {
+        self.changed.iter().find(|diff| diff.id == id)
+    }
+}
+
+fn membership(target: &TargetInfo) -> TargetTransition {
+    if target.is_in() {
+        TargetTransition::In
+    } else if target.is_draining() {
+        TargetTransition::Drain
+    } else {
+        TargetTransition::Out
+    }
+}
+
+fn transitions(old: &TargetInfo, new: &TargetInfo) -> Vec<TargetTransition> {
+    let mut transitions = Vec::new();
+    match (old.is_up(), new.is_up(), old.get_url(), new.get_url()) {
+        (false, true, _, Some(url)) => transitions.push(TargetTransition::Up(*url)),
+        (true, false, _, _) => transitions.push(TargetTransition::Down),
+        (true, true, Some(old_url), Some(new_url)) if old_url != new_url => {
+            transitions.push(TargetTransition::UrlChanged(*old_url, *new_url))
+        }
+        _ => {}
+    }
+    let new_membership = membership(new);
+    if new_membership != membership(old) {
+        transitions.push(new_membership);
+    }
+    if old.location != new.location {
+        transitions.push(TargetTransition::Moved(
+            old.location.clone(),
+            new.location.clone(),
+        ));
+    }
+    if old.weight != new.weight {
+        transitions.push(TargetTransition::WeightChanged(old.weight, new.weight));
+    }
+    if old.capacity != new.capacity {
+        transitions.push(TargetTransition::CapacityChanged(new.capacity));
+    }
+    if old.maintenance != new.maintenance {
+        transitions.push(TargetTransition::Maintenance(new.maintenance));
+    }
+    transitions
+}
+
+impl ClusterMap {
+    /// Compute changes from self to `other`.
+    ///
+    /// A target id reused by another uuid is reported as removed and added.
+    pub fn diff(&self, other: &ClusterMap) -> ClusterMapDiff {
+        let mut diff = ClusterMapDiff {
+            from: self.version,
+            to: other.version,
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for (&id, old) in &self.targets {
+            match other.get_target(id) {
+                Some(new) if new.uuid == old.uuid => {
+                    let transitions = transitions(old, new);
+                    if !transitions.is_empty() {
+                        diff.changed.push(TargetDiff {
+                            id,
+                            uuid: new.uuid,
+                            transitions,
+                        });
+                    }
+                }
+                _ => diff.removed.push(id),
+            }
+        }
+        for (&id, new) in &other.targets {
+            match self.get_target(id) {
+                Some(old) if old.uuid == new.uuid => {}
+                _ => diff.added.push(id),
+            }
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::TargetChangeOp;
+
+    #[test]
+    fn test_diff() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let url1: SocketAddr = "10.0.0.1:8000".parse().unwrap();
+        let url2: SocketAddr = "10.0.0.2:8000".parse().unwrap();
+        let location = TargetLocation::with_host("host", "dev").unwrap();
+        let create = |id| TargetChangeOp::Create(id, None, location.clone(), Default::default());
+
+        let map0 = ClusterMap::new_initial();
+        let log = map0
+            .change_builder()
+            .add(a, create(0))
+            .add(a, TargetChangeOp::Up(url1))
+            .add(b, create(1))
+            .build()
+            .unwrap();
+        let map1 = map0.apply_change(&log, None).unwrap();
+        let diff = map0.diff(&map1);
+        assert_eq!(diff.added, vec![0, 1]);
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+        assert!(map1.diff(&map1).is_empty());
+
+        let log = map1
+            .change_builder()
+            .add(a, TargetChangeOp::Up(url2))
+            .add(a, TargetChangeOp::In)
+            .add(b, TargetChangeOp::Remove)
+            .build()
+            .unwrap();
+        let map2 = map1.apply_change(&log, None).unwrap();
+        let diff = map1.diff(&map2);
+        assert_eq!((diff.from, diff.to), (map1.version, map2.version));
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed, vec![1]);
+        assert_eq!(
+            diff.get_target(0).unwrap().transitions,
+            vec![
+                TargetTransition::UrlChanged(url1, url2),
+                TargetTransition::In
+            ]
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// Max number of keys returned by `Conf::kv_get_all`.
+pub const KV_GET_ALL_LIMIT: usize = 1000;
+
+/// Id of a conf lease, the raft index it is granted at.
+pub type LeaseId = u64;
+
+/// One page of a conf scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvPage<T, K> {
+    pub items: Vec<T>,
+    /// Start key of the next page, None if scan is done.
+    pub next: Option<K>,
+}
+
+/// Change of a conf key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvEvent {
+    /// Key is put, `(key, value, mod revision)`
+    Put(Vec<u8>, Vec<u8>, u64),
+    /// Key is deleted, `(key, revision)`
+    Delete(Vec<u8>, u64),
+}
+
+impl KvEvent {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            KvEvent::Put(key, _, _) | KvEvent::Delete(key, _) => key,
+        }
+    }
+
+    /// Raft index where the change is committed.
+    pub fn revision(&self) -> u64 {
+        match self {
+            KvEvent::Put(_, _, revision) | KvEvent::Delete(_, revision) => *revision,
+        }
+    }
+}
+
+/// Watch on conf keys, dropping it cancels the watch.
+pub struct KvWatcher {
+    rx: mpsc::UnboundedReceiver<KvEvent>,
+    next_revision: u64,
+}
+
+impl KvWatcher {
+    pub(crate) fn new(rx: mpsc::UnboundedReceiver<KvEvent>, from_revision: u64) -> KvWatcher {
+        KvWatcher {
+            rx,
+            next_revision: from_revision,
+        }
+    }
+
+    /// Wait next event, None if the watch is closed by server.
+    pub async fn next(&mut self) -> Option<KvEvent> {
+        let event = self.rx.recv().await?;
+        self.next_revision = event.revision() + 1;
+        Some(event)
+    }
+
+    /// Revision to resume the watch from, after the last received event.
+    pub fn next_revision(&self) -> u64 {
+        self.next_revision
+    }
+
+    /// Cancel the watch.
+    pub fn cancel(self) {}
+}
+
+/// Guard of a conf transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compare {
+    /// Key exists, `(key)`
+    Exists(Vec<u8>),
+    /// Key exists with value, `(key, value)`
+    ValueEquals(Vec<u8>, Vec<u8>),
+    /// Mod revision of key equals, 0 for not exists, `(key, revision)`
+    RevisionEquals(Vec<u8>, u64),
+}
+
+/// Operation of a conf transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxnOp {
+    /// Get key, `(key)`
+    Get(Vec<u8>),
+    /// Put key, `(key, value)`
+    Put(Vec<u8>, Vec<u8>),
+    /// Put key and attach it to a lease, `(key, value, lease id)`
+    PutLease(Vec<u8>, Vec<u8>, LeaseId),
+    /// Delete key, `(key)`
+    Delete(Vec<u8>),
+}
+
+/// Result of a `TxnOp`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxnOpResult {
+    /// Value and mod revision, None if not exists
+    Get(Option<(Vec<u8>, u64)>),
+    /// New mod revision
+    Put(u64),
+    /// Key existed before delete
+    Delete(bool),
+}
+
+/// Result of a conf transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxnResponse {
+    /// All compares hold and success ops are executed, otherwise failure ops are executed.
+    pub succeeded: bool,
+    /// Results of executed ops, in order.
+    pub results: Vec<TxnOpResult>,
+}
@@ -1,4 +1,9 @@
 mod cluster;
+mod codec;
+mod diff;
+mod kv;
+mod oc;
+mod placement;
 mod target;
 mod traits;
 
@@ -6,6 +11,10 @@ pub mod storage_mod;
 pub mod ctl;
 
 pub use cluster::*;
+pub use codec::*;
+pub use diff::*;
+pub use kv::*;
+pub use oc::*;
 pub use target::*;
 pub use traits::*;
 
@@ -17,9 +26,9 @@ pub enum Error {
     #[error("madsim RPC io error: {0}")]
     IoError(#[from] std::io::Error),
 
-    /// Error from `etcd_client`
-    // #[error("etcd error: {0}")]
-    // EtcdError(#[from] etcd_client::Error),
+    /// Error from storage engine
+    #[error("storage error: {0}")]
+    StorageError(#[from] storage_mod::Error),
 
     /// Invalid arguments
     #[error("invalid arguments")]
@@ -37,13 +46,13 @@ pub enum Error {
     #[error("transaction conflict, try again")]
     TxnConflict,
 
-    /// Transaction conflict too may times
-    #[error("transaction conflict too may times.")]
-    TxnMaxRetry,
+    /// Proposal dropped by raft before commit, eg: leader changed, try again
+    #[error("proposal dropped, try again")]
+    ProposalDropped,
 
-    /// Watch return None
-    #[error("watch return None, maybe already canceled")]
-    WatchReturnNone,
+    /// Raft log at revision is compacted, can't resume from it, fetch full state
+    #[error("revision {0} is compacted")]
+    RevisionCompacted(cluster::Revision),
 
     /// Server lost leadership
     #[error("server is not leader")]
@@ -64,4 +73,56 @@ pub enum Error {
     /// Some targets not started
     #[error("some targets not started")]
     TargetsNotStarted,
+
+    /// Changelog can't be applied to cluster map, refetch and retry
+    #[error("invalid changelog: {0}")]
+    InvalidChangeLog(#[from] cluster::ChangeLogError),
+
+    /// Failed to decode cluster map or changelog
+    #[error("decode error: {0}")]
+    Codec(#[from] codec::CodecError),
+
+    /// Invalid target state transition
+    #[error("invalid target state transition: {0}")]
+    InvalidTransition(#[from] target::TransitionError),
+
+    /// Too many keys for one request, use paginated scan, `(limit)`
+    #[error("too many keys, limit {0}")]
+    TooManyKeys(usize),
+
+    /// Oid counter overflow
+    #[error("oid exhausted")]
+    OidExhausted,
+
+    /// Not enough IN targets to place a stripe, `(need, have)`
+    #[error("not enough IN targets, need {0}, have {1}")]
+    NotEnoughTargets(u32, u32),
+
+    /// Lease is revoked or expired, `(lease id)`
+    #[error("lease {0} not found")]
+    LeaseNotFound(kv::LeaseId),
+
+    /// Chunk or stripe type not found, `(id)`
+    #[error("object class type {0} not found")]
+    OcTypeNotFound(u8),
+
+    /// Chunk or stripe type is deprecated, not for new objects, `(id)`
+    #[error("object class type {0} is deprecated")]
+    OcTypeDeprecated(u8),
+
+    /// Only deprecated chunk or stripe type can be deleted, `(id)`
+    #[error("object class type {0} is not deprecated")]
+    OcTypeNotDeprecated(u8),
+
+    /// Chunk or stripe type is still used by objects, can't be deleted, `(id, refs)`
+    #[error("object class type {0} is used by {1} objects")]
+    OcTypeInUse(u8, u64),
+
+    /// Object class with same name exists
+    #[error("object class with same name exists")]
+    OcClassExists,
+
+    /// Not enough failure domains for stripe width of an object class, `(need, have)`
+    #[error("not enough failure domains, need {0}, have {1}")]
+    NotEnoughDomains(u32, u32),
 }
@@ -0,0 +1,377 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{ClusterMap, Codec, Error, FailureDomain};
+
+/// Max number of chunk or stripe types, ids are `u8`.
+pub const MAX_OC_TYPES: usize = u8::MAX as usize + 1;
+
+/// Lifecycle of a chunk or stripe type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OcTypeState {
+    /// Can be used by new objects.
+    Active,
+    /// Only used by existing objects, deleted after they are gone.
+    Deprecated,
+}
+
+/// Kind of an `OcTable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OcKind {
+    Chunk,
+    Stripe,
+}
+
+impl OcKind {
+    /// Error of creating a type when all ids are used.
+    pub fn full_error(self) -> Error {
+        match self {
+            OcKind::Chunk => Error::MaxChunkType,
+            OcKind::Stripe => Error::MaxStripeType,
+        }
+    }
+}
+
+/// Chunk or stripe type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OcType {
+    /// Chunk size or stripe count.
+    pub size: u32,
+    pub state: OcTypeState,
+    /// Number of objects using it.
+    pub refs: u64,
+}
+
+/// Chunk or stripe types indexed by id.
+///
+/// Id of a deleted type is reused by the next created type, so a type
+/// is only deleted after all objects using it release it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OcTable {
+    types: Vec<Option<OcType>>,
+}
+
+impl Codec for OcTable {
+    const KIND: u8 = 6;
+}
+
+impl OcTable {
+    /// Create an active type with the lowest free id, None if all ids are used.
+    pub fn create(&mut self, size: u32) -> Option<u8> {
+        let ty = Some(OcType {
+            size,
+            state: OcTypeState::Active,
+            refs: 0,
+        });
+        let id = match self.types.iter().position(Option::is_none) {
+            Some(id) => id,
+            None if self.types.len() < MAX_OC_TYPES => {
+                self.types.push(None);
+                self.types.len() - 1
+            }
+            None => return None,
+        };
+        self.types[id] = ty;
+        Some(id as u8)
+    }
+
+    /// Get type `id`, including deprecated ones.
+    pub fn get(&self, id: u8) -> Option<OcType> {
+        self.types.get(id as usize).copied().flatten()
+    }
+
+    /// Get size of type `id` for a new object, `Error::OcTypeDeprecated` if deprecated.
+    pub fn get_active(&self, id: u8) -> Result<Option<u32>, Error> {
+        match self.get(id) {
+            Some(ty) if ty.state == OcTypeState::Deprecated => Err(Error::OcTypeDeprecated(id)),
+            ty => Ok(ty.map(|ty| ty.size)),
+        }
+    }
+
+    fn get_mut(&mut self, id: u8) -> Result<&mut OcType, Error> {
+        self.types
+            .get_mut(id as usize)
+            .and_then(Option::as_mut)
+            .ok_or(Error::OcTypeNotFound(id))
+    }
+
+    /// Count a new object as user of type `id`, return its size.
+    ///
+    /// Return `Error::OcTypeDeprecated` if deprecated.
+    pub fn acquire(&mut self, id: u8) -> Result<u32, Error> {
+        let ty = self.get_mut(id)?;
+        if ty.state == OcTypeState::Deprecated {
+            return Err(Error::OcTypeDeprecated(id));
+        }
+        ty.refs += 1;
+        Ok(ty.size)
+    }
+
+    /// Release type `id` used by a deleted object.
+    pub fn release(&mut self, id: u8) -> Result<(), Error> {
+        let ty = self.get_mut(id)?;
+        ty.refs = ty.refs.checked_sub(1).ok_or(Error::InvalidArg)?;
+        Ok(())
+    }
+
+    /// Mark type `id` deprecated, no new objects may use it.
+    pub fn deprecate(&mut self, id: u8) -> Result<(), Error> {
+        self.get_mut(id)?.state = OcTypeState::Deprecated;
+        Ok(())
+    }
+
+    /// Delete deprecated type `id` and free the id.
+    ///
+    /// Return `Error::OcTypeInUse` if some objects haven't released it.
+    pub fn delete(&mut self, id: u8) -> Result<(), Error> {
+        match self.get(id) {
+            None => return Err(Error::OcTypeNotFound(id)),
+            Some(ty) if ty.state == OcTypeState::Active => {
+                return Err(Error::OcTypeNotDeprecated(id))
+            }
+            Some(ty) if ty.refs > 0 => return Err(Error::OcTypeInUse(id, ty.refs)),
+            Some(_) => self.types[id as usize] = None,
+        }
+        while matches!(self.types.last(), Some(None)) {
+            self.types.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Erasure coding algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EcAlgorithm {
+    /// Reed-Solomon, at least one parity chunk.
+    ReedSolomon,
+    /// XOR of data chunks, exactly one parity chunk.
+    Xor,
+}
+
+/// How objects of a class survive failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Redundancy {
+    /// Full copies of each chunk, `(replicas)`
+    Replication(u32),
+    /// Data chunks and parity chunks computed from them.
+    ErasureCode {
+        data: u32,
+        parity: u32,
+        algorithm: EcAlgorithm,
+    },
+}
+
+/// Object class descriptor, stored by controller state machine, see `Conf::oc_create_class`.
+///
+/// Encoded as `VersionedClass`, which carries its own version,
+/// so fields are added without a `FORMAT_VERSION` bump.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "VersionedClass", into = "VersionedClass")]
+pub struct ObjectClass {
+    pub name: String,
+    pub chunk_size: u32,
+    pub redundancy: Redundancy,
+    /// Chunks of a stripe are placed in distinct failure domains at this level.
+    pub failure_domain: FailureDomain,
+}
+
+impl Codec for ObjectClass {
+    const KIND: u8 = 5;
+}
+
+/// Encoded layouts of `ObjectClass`, variant index is the version.
+///
+/// To add fields, add a variant with the new layout and encode it,
+/// decode older variants with defaults for the new fields.
+#[derive(Serialize, Deserialize)]
+enum VersionedClass {
+    V1 {
+        name: String,
+        chunk_size: u32,
+        redundancy: Redundancy,
+        failure_domain: FailureDomain,
+    },
+}
+
+impl From<VersionedClass> for ObjectClass {
+    fn from(class: VersionedClass) -> ObjectClass {
+        match class {
+            VersionedClass::V1 {
+                name,
+                chunk_size,
+                redundancy,
+                failure_domain,
+            } => ObjectClass {
+                name,
+                chunk_size,
+                redundancy,
+                failure_domain,
+            },
+        }
+    }
+}
+
+impl From<ObjectClass> for VersionedClass {
+    fn from(class: ObjectClass) -> VersionedClass {
+        VersionedClass::V1 {
+            name: class.name,
+            chunk_size: class.chunk_size,
+            redundancy: class.redundancy,
+            failure_domain: class.failure_domain,
+        }
+    }
+}
+
+impl ObjectClass {
+    /// Number of chunks in a stripe.
+    pub fn stripe_width(&self) -> u32 {
+        match self.redundancy {
+            Redundancy::Replication(replicas) => replicas,
+            Redundancy::ErasureCode { data, parity, .. } => data.saturating_add(parity),
+        }
+    }
+
+    /// Check parameters, and that `map` has a failure domain for each chunk of a stripe.
+    pub fn validate(&self, map: &ClusterMap) -> Result<(), Error> {
+        if self.name.is_empty() || self.chunk_size == 0 {
+            return Err(Error::InvalidArg);
+        }
+        match self.redundancy {
+            Redundancy::Replication(0) => return Err(Error::InvalidArg),
+            Redundancy::ErasureCode { data: 0, .. } | Redundancy::ErasureCode { parity: 0, .. } => {
+                return Err(Error::InvalidArg)
+            }
+            Redundancy::ErasureCode {
+                parity,
+                algorithm: EcAlgorithm::Xor,
+                ..
+            } if parity != 1 => return Err(Error::InvalidArg),
+            _ => {}
+        }
+        let need = self.stripe_width();
+        let have = map.failure_domains(self.failure_domain) as u32;
+        if need > have {
+            return Err(Error::NotEnoughDomains(need, have));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TargetCapacity, TargetInfo, TargetLocation};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_oc_table() {
+        let mut table = OcTable::default();
+        for i in 0..MAX_OC_TYPES {
+            assert_eq!(table.create(4096 + i as u32), Some(i as u8));
+        }
+        assert_eq!(table.create(1), None);
+
+        assert!(matches!(
+            table.delete(3),
+            Err(Error::OcTypeNotDeprecated(3))
+        ));
+        table.deprecate(3).unwrap();
+        assert!(matches!(
+            table.get_active(3),
+            Err(Error::OcTypeDeprecated(3))
+        ));
+        // existing objects still read it
+        assert_eq!(table.get(3).unwrap().size, 4099);
+        table.delete(3).unwrap();
+        assert_eq!(table.get(3), None);
+        assert_eq!(table.create(4099), Some(3));
+        assert_eq!(table.acquire(3).unwrap(), 4099);
+        assert_eq!(table.acquire(3).unwrap(), 4099);
+        table.deprecate(3).unwrap();
+        assert!(matches!(table.acquire(3), Err(Error::OcTypeDeprecated(3))));
+
+        // not reused while objects use it
+        assert!(matches!(table.delete(3), Err(Error::OcTypeInUse(3, 2))));
+        table.release(3).unwrap();
+        assert!(matches!(table.delete(3), Err(Error::OcTypeInUse(3, 1))));
+        table.release(3).unwrap();
+        assert!(matches!(table.release(3), Err(Error::InvalidArg)));
+        assert_eq!(OcTable::decode(&table.encode()).unwrap(), table);
+        table.delete(3).unwrap();
+        assert_eq!(table.get(3), None);
+        assert_eq!(table.get_active(3).unwrap(), None);
+        assert!(matches!(table.deprecate(3), Err(Error::OcTypeNotFound(3))));
+        assert!(matches!(table.delete(3), Err(Error::OcTypeNotFound(3))));
+
+        // freed id is reused
+        assert_eq!(table.create(1), Some(3));
+        assert_eq!(table.get_active(3).unwrap(), Some(1));
+        assert_eq!(table.create(1), None);
+    }
+
+    #[test]
+    fn test_object_class() {
+        let mut map = ClusterMap::new_initial();
+        for id in 0..8 {
+            let rack = format!("rack{}", id / 2);
+            let location =
+                TargetLocation::new("r", "z", &rack, &format!("host{}", id), "dev").unwrap();
+            let capacity = TargetCapacity::new(4 << 40, 0);
+            let target = TargetInfo::new(Uuid::new_v4(), id, None, true, location, capacity);
+            map.uuid_map.insert(target.uuid, id);
+            map.targets.insert(id, target);
+        }
+        assert_eq!(map.failure_domains(FailureDomain::Rack), 4);
+        assert_eq!(map.failure_domains(FailureDomain::Host), 8);
+
+        let mut class = ObjectClass {
+            name: "ec-4-2".to_owned(),
+            chunk_size: 1 << 20,
+            redundancy: Redundancy::ErasureCode {
+                data: 4,
+                parity: 2,
+                algorithm: EcAlgorithm::ReedSolomon,
+            },
+            failure_domain: FailureDomain::Host,
+        };
+        assert_eq!(class.stripe_width(), 6);
+        class.validate(&map).unwrap();
+        assert_eq!(ObjectClass::decode(&class.encode()).unwrap(), class);
+
+        class.failure_domain = FailureDomain::Rack;
+        assert!(matches!(
+            class.validate(&map),
+            Err(Error::NotEnoughDomains(6, 4))
+        ));
+        class.redundancy = Redundancy::Replication(3);
+        class.validate(&map).unwrap();
+        class.redundancy = Redundancy::Replication(0);
+        assert!(matches!(class.validate(&map), Err(Error::InvalidArg)));
+        class.redundancy = Redundancy::ErasureCode {
+            data: 2,
+            parity: 2,
+            algorithm: EcAlgorithm::Xor,
+        };
+        assert!(matches!(class.validate(&map), Err(Error::InvalidArg)));
+        class.redundancy = Redundancy::ErasureCode {
+            data: 3,
+            parity: 0,
+            algorithm: EcAlgorithm::ReedSolomon,
+        };
+        assert!(matches!(class.validate(&map), Err(Error::InvalidArg)));
+    }
+
+    #[test]
+    fn test_object_class_v1() {
+        let class = ObjectClass {
+            name: "rep-3".to_owned(),
+            chunk_size: 4096,
+            redundancy: Redundancy::Replication(3),
+            failure_domain: FailureDomain::Rack,
+        };
+        // written by version 1, must decode after new versions are added
+        let v1 = [
+            5, 2, 0, 5, 114, 101, 112, 45, 51, 251, 0, 16, 0, 3, 2, 224, 173, 45, 237,
+        ];
+        assert_eq!(ObjectClass::decode(&v1).unwrap(), class);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{
+    cluster::ClusterMap,
+    target::{FailureDomain, TargetId, TargetInfo},
+    Error,
+};
+use std::collections::HashSet;
+
+/// Deterministic 64-bit mixer (splitmix64 finalizer).
+///
+/// Placement must be identical on every node, so we can't use std hashers,
+/// whose output is not guaranteed to be stable across builds.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// `log2(x)` in 32.32 fixed point, `x` must be positive.
+///
+/// Computed with integer arithmetic only, so it is identical on every platform.
+fn log2_fixed(x: u64) -> u64 {
+    debug_assert!(x > 0);
+    let int = 63 - x.leading_zeros() as u64;
+    // mantissa in [1, 2), 62 fractional bits
+    let one: u128 = 1 << 62;
+    let mut m = (x as u128) << (62 - int);
+    let mut frac = 0;
+    for bit in (0..32).rev() {
+        m = (m * m) >> 62;
+        if m >= 2 * one {
+            m >>= 1;
+            frac |= 1 << bit;
+        }
+    }
+    (int << 32) | frac
+}
+
+/// Weighted rendezvous draw of target `id` for stripe of object `oid`.
+///
+/// The draw is `-log2(u)` in fixed point, where `u` is a hash in (0, 1].
+/// Targets are ranked by `draw / weight` ascending, which picks each target
+/// with probability proportional to its weight (straw2 in CRUSH).
+fn draw(oid: u64, stripe_type: u8, id: TargetId) -> u64 {
+    let key = mix64(mix64(oid).wrapping_add(stripe_type as u64));
+    let u = (mix64(key ^ mix64(id as u64)) >> 32) + 1;
+    (32 << 32) - log2_fixed(u)
+}
+
+impl ClusterMap {
+    /// Place stripe of object `oid` on `stripe_cnt` distinct IN targets.
+    ///
+    /// Placement uses weighted rendezvous hashing: every IN target gets a pseudo random
+    /// draw for the object scaled by its weight, and targets are picked in draw order,
+    /// so targets receive data in proportion to their weight. Targets with weight 0
+    /// are never picked.
+    ///
+    /// Targets are spread over failure domains from the widest level to the narrowest:
+    /// we first pick at most one target per region, then per zone, and so on until
+    /// `stripe_cnt` targets are picked.
+    ///
+    /// The result only depends on the map, so all nodes with the same map version get the
+    /// same placement. When a target goes OUT, only stripes that used it (or the failure
+    /// domains it belonged to) are moved.
+    ///
+    /// DRAIN targets are excluded, this is where new writes go. Use `locate` to find
+    /// where existing data is.
+    pub fn place(&self, oid: u64, stripe_type: u8, stripe_cnt: u32) -> Result<Vec<TargetId>, Error> {
+        self.place_on(oid, stripe_type, stripe_cnt, |target| target.is_in())
+    }
+
+    /// Locate stripe of object `oid` for reads.
+    ///
+    /// Same as `place`, but DRAIN targets are still candidates: they keep data until it
+    /// is migrated to the targets `place` picks, so stripes written before drain started
+    /// stay findable.
+    pub fn locate(&self, oid: u64, stripe_type: u8, stripe_cnt: u32) -> Result<Vec<TargetId>, Error> {
+        self.place_on(oid, stripe_type, stripe_cnt, |target| {
+            target.is_in() || target.is_draining()
+        })
+    }
+
+    /// Place stripe on `stripe_cnt` distinct targets accepted by `usable` with non-zero weight.
+    fn place_on<F>(
+        &self,
+        oid: u64,
+        stripe_type: u8,
+        stripe_cnt: u32,
+        usable: F,
+    ) -> Result<Vec<TargetId>, Error>
+    where
+        F: Fn(&TargetInfo) -> bool,
+    {
+        let mut candidates: Vec<(u64, TargetId, &TargetInfo)> = self
+            .targets
+            .iter()
+            .filter(|(_, target)| usable(target) && target.weight > 0)
+            .map(|(&id, target)| (draw(oid, stripe_type, id), id, target))
+            .collect();
+        let cnt = stripe_cnt as usize;
+        if candidates.len() < cnt {
+            return Err(Error::NotEnoughTargets(stripe_cnt, candidates.len() as u32));
+        }
+        // compare draw_a / weight_a with draw_b / weight_b
+        candidates.sort_by(|a, b| {
+            let lhs = a.0 as u128 * b.2.weight as u128;
+            let rhs = b.0 as u128 * a.2.weight as u128;
+            lhs.cmp(&rhs).then(a.1.cmp(&b.1))
+        });
+
+        let mut picked = vec![false; candidates.len()];
+        let mut placement = Vec::with_capacity(cnt);
+        for level in FailureDomain::ALL {
+            if placement.len() == cnt {
+                break;
+            }
+            let mut used: HashSet<String> = candidates
+                .iter()
+                .zip(&picked)
+                .filter(|(_, &picked)| picked)
+                .map(|((_, _, target), _)| target.location.domain_path(level))
+                .collect();
+            for (i, (_, id, target)) in candidates.iter().enumerate() {
+                if placement.len() == cnt {
+                    break;
+                }
+                if !picked[i] && used.insert(target.location.domain_path(level)) {
+                    picked[i] = true;
+                    placement.push(*id);
+                }
+            }
+        }
+
+        // targets share the same location, fallback to draw order
+        for (i, (_, id, _)) in candidates.iter().enumerate() {
+            if placement.len() == cnt {
+                break;
+            }
+            if !picked[i] {
+                placement.push(*id);
+            }
+        }
+
+        Ok(placement)
+    }
+
+    /// Number of failure domains at `level` with targets placement can use.
+    pub fn failure_domains(&self, level: FailureDomain) -> usize {
+        self.targets
+            .values()
+            .filter(|target| target.is_in() && target.weight > 0)
+            .map(|target| target.location.domain_path(level))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::{TargetCapacity, TargetLocation};
+    use uuid::Uuid;
+
+    fn build_map(racks: u32, hosts: u32, devices: u32) -> ClusterMap {
+        let mut map = ClusterMap::new_initial();
+        for rack in 0..racks {
+            for host in 0..hosts {
+                for device in 0..devices {
+                    let id = (rack * hosts + host) * devices + device;
+                    let location = TargetLocation::new(
+                        "r",
+                        "z",
+                        &format!("rack{}", rack),
+                        &format!("host{}-{}", rack, host),
+                        &format!("dev{}", device),
+                    )
+                    .unwrap();
+                    let capacity = TargetCapacity::new(4 << 40, 0);
+                    let target =
+                        TargetInfo::new(Uuid::new_v4(), id, None, true, location, capacity);
+                    map.uuid_map.insert(target.uuid, id);
+                    map.targets.insert(id, target);
+                }
+            }
+        }
+        map
+    }
+
+    #[test]
+    fn test_place_spread() {
+        let map = build_map(4, 3, 2);
+        for oid in 0..100 {
+            let placement = map.place(oid, 0, 4).unwrap();
+            assert_eq!(placement, map.place(oid, 0, 4).unwrap());
+            let racks: HashSet<_> = placement
+                .iter()
+                .map(|&id| map.get_target(id).unwrap().location.rack.clone())
+                .collect();
+            assert_eq!(racks.len(), 4);
+
+            // more targets than racks, spread over hosts
+            let placement = map.place(oid, 0, 12).unwrap();
+            let hosts: HashSet<_> = placement
+                .iter()
+                .map(|&id| map.get_target(id).unwrap().location.host.clone())
+                .collect();
+            assert_eq!(hosts.len(), 12);
+        }
+        assert!(map.place(0, 0, 25).is_err());
+    }
+
+    #[test]
+    fn test_place_minimal_movement() {
+        let map = build_map(8, 4, 1);
+        let mut out_map = map.clone();
+        let out = out_map.get_target(5).unwrap().remove_out().unwrap();
+        out_map.targets.insert(5, out);
+
+        let mut moved = 0;
+        for oid in 0..1000 {
+            let before: HashSet<_> = map.place(oid, 1, 3).unwrap().into_iter().collect();
+            let after: HashSet<_> = out_map.place(oid, 1, 3).unwrap().into_iter().collect();
+            assert!(!after.contains(&5));
+            moved += before.difference(&after).count();
+            if !before.contains(&5) {
+                assert_eq!(before, after);
+            }
+        }
+        assert!(moved < 1000 * 3 / 8);
+    }
+
+    #[test]
+    fn test_failure_domains() {
+        let mut map = build_map(4, 2, 1);
+        assert_eq!(map.failure_domains(FailureDomain::Rack), 4);
+        assert_eq!(map.failure_domains(FailureDomain::Host), 8);
+
+        // both hosts of rack0 can't be used, one OUT and one with zero weight
+        let out = map.get_target(0).unwrap().remove_out().unwrap();
+        map.targets.insert(0, out);
+        map.targets.insert(1, map.get_target(1).unwrap().set_weight(0));
+        assert_eq!(map.failure_domains(FailureDomain::Rack), 3);
+        assert_eq!(map.failure_domains(FailureDomain::Host), 6);
+
+        // one usable target keeps its domain
+        map.targets.insert(1, map.get_target(1).unwrap().set_weight(4));
+        assert_eq!(map.failure_domains(FailureDomain::Rack), 4);
+        assert_eq!(map.failure_domains(FailureDomain::Host), 7);
+    }
+
+    #[test]
+    fn test_log2_fixed() {
+        for x in [1u64, 2, 3, 1000, 1 << 31, u32::MAX as u64, 1 << 32] {
+            let expect = (x as f64).log2();
+            let got = log2_fixed(x) as f64 / (1u64 << 32) as f64;
+            assert!((expect - got).abs() < 1e-8, "{} {} {}", x, expect, got);
+        }
+    }
+
+    #[test]
+    fn test_place_weighted() {
+        let mut map = build_map(1, 2, 1);
+        // 4TB and 16TB drive
+        map.targets.insert(1, map.get_target(1).unwrap().set_weight(4 * 4));
+        map.targets.insert(0, map.get_target(0).unwrap().set_weight(4));
+
+        let cnt = 20000;
+        let small = (0..cnt)
+            .filter(|&oid| map.place(oid, 0, 1).unwrap() == vec![0])
+            .count();
+        let ratio = small as f64 / cnt as f64;
+        assert!((ratio - 0.2).abs() < 0.02, "{}", ratio);
+
+        map.targets.insert(0, map.get_target(0).unwrap().set_weight(0));
+        assert_eq!(map.place(0, 0, 1).unwrap(), vec![1]);
+        assert!(map.place(0, 0, 2).is_err());
+    }
+
+    #[test]
+    fn test_place_drain() {
+        let map = build_map(8, 4, 1);
+        let mut drain_map = map.clone();
+        let drain = drain_map.get_target(5).unwrap().drain().unwrap();
+        drain_map.targets.insert(5, drain);
+
+        let mut drained = 0;
+        for oid in 0..1000 {
+            let before = map.place(oid, 1, 3).unwrap();
+            // existing data is still found on the draining target
+            assert_eq!(drain_map.locate(oid, 1, 3).unwrap(), before);
+            let after = drain_map.place(oid, 1, 3).unwrap();
+            assert!(!after.contains(&5));
+            if before.contains(&5) {
+                drained += 1;
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+        assert!(drained > 0);
+        assert_eq!(drain_map.failure_domains(FailureDomain::Host), 31);
+    }
+}
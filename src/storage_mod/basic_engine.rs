@@ -1,11 +1,11 @@
 use std::fs;
 use std::path::Path;
 use std::sync::Arc;
-use rocksdb::{DBIterator, DB};
+use rocksdb::{DBIterator, Direction, IteratorMode, WriteBatch, DB};
 use raft::eraftpb::Entry;
 use super::peer_traits::{KvEngine, RaftEngine};
 use super::{common::*, utils::*};
-use crate::{ClusterMapVersion, ChangeLog};
+use crate::{ClusterMapVersion, ChangeLog, Revision};
 
 #[derive(Clone, Debug)]
 pub struct BasicEngine{
@@ -49,7 +49,7 @@ impl BasicEngine{
 }
 
 impl KvEngine for BasicEngine{
-    fn get_range(&self, from: ClusterMapVersion, to: Option<ClusterMapVersion>,) ->std::result::Result<(Vec<ChangeLog>, i64), Error>{
+    fn get_range(&self, from: ClusterMapVersion, to: Option<ClusterMapVersion>,) ->std::result::Result<(Vec<ChangeLog>, Revision), Error>{
         todo!();
     }
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()>{
@@ -67,6 +67,25 @@ impl KvEngine for BasicEngine{
         }
     }
 
+    fn scan(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>{
+        let iter = self.db.iterator(IteratorMode::From(start, Direction::Forward));
+        Ok(iter
+            .take_while(|(key, _)| key.as_ref() < end)
+            .take(limit)
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect())
+    }
+
+    fn scan_keys(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Vec<u8>>>{
+        let iter = self.db.iterator(IteratorMode::From(start, Direction::Forward));
+        Ok(iter
+            .map(|(key, _)| key)
+            .take_while(|key| key.as_ref() < end)
+            .take(limit)
+            .map(|key| key.to_vec())
+            .collect())
+    }
+
     fn delete(&self, key: &[u8]) -> Result<()>{
         match self.db.delete(key){
             Ok(_) => Ok(()),
@@ -76,6 +95,19 @@ impl KvEngine for BasicEngine{
     fn delete_range(&self, begin_key: &[u8], end_key: &[u8]) -> Result<()>{
         todo!("wrap rocksDB to support this range delection");
     }
+    fn write_batch(&self, batch: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<()>{
+        let mut wb = WriteBatch::default();
+        for (key, value) in batch{
+            match value{
+                Some(value) => wb.put(key, value),
+                None => wb.delete(key),
+            }
+        }
+        match self.db.write(wb){
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::Engine("write batch error".to_owned())),
+        }
+    }
     ///todo: error handle should be included
     fn put_msg<M: protobuf::Message>(&self, key: &[u8], m: &M) -> Result<()>{
         self.put(key, &m.write_to_bytes().unwrap())
@@ -122,6 +154,35 @@ mod tests{
         assert_eq!(None, engine.get(key2));
     }
 
+    #[test]
+    fn test_scan(){
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let db = DB::open_default(path).unwrap();
+        let engine = BasicEngine::from_db(Arc::new(db));
+        for key in [b"a", b"b1", b"b2", b"b3", b"c"]{
+            engine.put(key, b"value").unwrap();
+        }
+        let keys = |items: Vec<(Vec<u8>, Vec<u8>)>| items.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(vec![b"b1".to_vec(), b"b2".to_vec(), b"b3".to_vec()], keys(engine.scan(b"b", b"c", 10).unwrap()));
+        assert_eq!(vec![b"b2".to_vec()], keys(engine.scan(b"b2", b"c", 1).unwrap()));
+        assert!(engine.scan(b"d", b"e", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_batch(){
+        let path = Builder::new().prefix("var").tempdir().unwrap();
+        let db = DB::open_default(path).unwrap();
+        let engine = BasicEngine::from_db(Arc::new(db));
+        engine.put(b"key1", b"value1").unwrap();
+        let batch = vec![
+            (b"key1".to_vec(), None),
+            (b"key2".to_vec(), Some(b"value2".to_vec())),
+        ];
+        engine.write_batch(&batch).unwrap();
+        assert_eq!(None, engine.get(b"key1"));
+        assert_eq!(Some(b"value2".to_vec()), engine.get(b"key2"));
+    }
+
     #[test]
     fn test_rocksdb(){
         let path = Builder::new().prefix("var").tempdir().unwrap();
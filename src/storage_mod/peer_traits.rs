@@ -1,14 +1,20 @@
 
 use super::common::*;
 use raft::eraftpb::Entry;
-use crate::{ClusterMapVersion, ChangeLog};
+use crate::{ClusterMapVersion, ChangeLog, Revision};
 
 pub trait KvEngine: Send + Sync{
-    fn get_range(&self, from: ClusterMapVersion, to: Option<ClusterMapVersion>,) ->std::result::Result<(Vec<ChangeLog>, i64), Error>;
+    fn get_range(&self, from: ClusterMapVersion, to: Option<ClusterMapVersion>,) ->std::result::Result<(Vec<ChangeLog>, Revision), Error>;
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    ///get at most `limit` key-value pairs in [start, end) in key order
+    fn scan(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    ///get at most `limit` keys in [start, end) in key order, values are not copied
+    fn scan_keys(&self, start: &[u8], end: &[u8], limit: usize) -> Result<Vec<Vec<u8>>>;
     fn delete(&self, key: &[u8]) -> Result<()>;
     fn delete_range(&self, begin_key: &[u8], end_key: &[u8]) -> Result<()>;
+    ///write all puts and deletes(`None` value) atomically
+    fn write_batch(&self, batch: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<()>;
     fn put_msg<M: protobuf::Message>(&self, key: &[u8], m: &M) -> Result<()>;
 }
 
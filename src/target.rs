@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
 use uuid::Uuid;
 
 /// Id of target.
@@ -16,6 +16,14 @@ pub struct TargetInfo {
     pub uuid: Uuid,
     pub state: TargetState,
     pub location: TargetLocation,
+    /// Capacity reported by the target.
+    pub capacity: TargetCapacity,
+    /// Placement weight, data is assigned to IN targets in proportion to weight.
+    ///
+    /// Initialized from capacity at registration, can be changed by operator.
+    pub weight: u32,
+    /// Target is under maintenance, controller won't mark it DOWN/OUT automatically.
+    pub maintenance: bool,
 }
 
 impl TargetInfo {
@@ -23,61 +31,102 @@ impl TargetInfo {
     /// - uuid: target uuid.
     /// - url: target url.
     /// - id: id of the target(optional).
-    pub fn new_init(uuid: Uuid, url: SocketAddr, id: Option<TargetId>) -> TargetInfo {
+    /// - location: where the target is placed.
+    /// - capacity: capacity of the target, used as initial weight.
+    pub fn new_init(
+        uuid: Uuid,
+        url: SocketAddr,
+        id: Option<TargetId>,
+        location: TargetLocation,
+        capacity: TargetCapacity,
+    ) -> TargetInfo {
         TargetInfo {
             uuid,
             state: TargetState::Init(url, id),
-            location: TargetLocation::new(),
+            location,
+            capacity,
+            weight: capacity.default_weight(),
+            maintenance: false,
         }
     }
 
-    pub(crate) fn new(uuid: Uuid, id: TargetId, url: Option<SocketAddr>, in_: bool) -> TargetInfo {
+    pub(crate) fn new(
+        uuid: Uuid,
+        id: TargetId,
+        url: Option<SocketAddr>,
+        in_: bool,
+        location: TargetLocation,
+        capacity: TargetCapacity,
+    ) -> TargetInfo {
         TargetInfo {
             uuid,
             state: TargetState::new(id, url, in_),
-            location: TargetLocation::new(),
+            location,
+            capacity,
+            weight: capacity.default_weight(),
+            maintenance: false,
         }
     }
 
-    pub fn add_in(&self) -> TargetInfo {
-        let new_state = self.state.add_in().unwrap_or_else(|| self.state.clone());
-        TargetInfo {
-            state: new_state,
+    pub fn add_in(&self) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.add_in()?)
+    }
+
+    pub fn remove_out(&self) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.remove_out()?)
+    }
+
+    pub fn drain(&self) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.drain()?)
+    }
+
+    pub fn up(&self, url: SocketAddr) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.up(url)?)
+    }
+
+    pub fn down(&self) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.down()?)
+    }
+
+    pub fn init(&self, id: u32, up: bool, in_: bool) -> Result<TargetInfo, TransitionError> {
+        self.with_state(self.state.init(id, up, in_)?)
+    }
+
+    fn with_state(&self, state: TargetState) -> Result<TargetInfo, TransitionError> {
+        Ok(TargetInfo {
+            state,
             ..self.clone()
-        }
+        })
     }
 
-    pub fn remove_out(&self) -> TargetInfo {
-        let new_state = self
-            .state
-            .remove_out()
-            .unwrap_or_else(|| self.state.clone());
+    /// Move target to a new location.
+    pub fn move_to(&self, location: TargetLocation) -> TargetInfo {
         TargetInfo {
-            state: new_state,
+            location,
             ..self.clone()
         }
     }
 
-    pub fn up(&self, url: SocketAddr) -> TargetInfo {
-        let new_state = self.state.up(url).unwrap_or_else(|| self.state.clone());
+    /// Update capacity reported by target, weight is not changed.
+    pub fn report_capacity(&self, capacity: TargetCapacity) -> TargetInfo {
         TargetInfo {
-            state: new_state,
+            capacity,
             ..self.clone()
         }
     }
 
-    pub fn down(&self) -> TargetInfo {
-        let new_state = self.state.down().unwrap_or_else(|| self.state.clone());
+    /// Set placement weight of target.
+    pub fn set_weight(&self, weight: u32) -> TargetInfo {
         TargetInfo {
-            state: new_state,
+            weight,
             ..self.clone()
         }
     }
 
-    pub fn init(&self, id: u32, up: bool, in_: bool) -> TargetInfo {
-        let new_state = self.state.init(id, up, in_);
+    /// Enter or leave maintenance mode.
+    pub fn set_maintenance(&self, maintenance: bool) -> TargetInfo {
         TargetInfo {
-            state: new_state,
+            maintenance,
             ..self.clone()
         }
     }
@@ -94,6 +143,20 @@ impl TargetInfo {
         self.state.is_in()
     }
 
+    pub fn is_draining(&self) -> bool {
+        self.state.is_draining()
+    }
+
+    /// Only DownOut targets can be removed from cluster map.
+    pub fn is_removable(&self) -> bool {
+        matches!(self.state, TargetState::DownOut(_))
+    }
+
+    /// Controller may mark target DOWN/OUT when it stops sending heartbeat.
+    pub fn allow_auto_down(&self) -> bool {
+        !self.maintenance
+    }
+
     pub fn get_url(&self) -> Option<&SocketAddr> {
         self.state.get_url()
     }
@@ -103,10 +166,55 @@ impl TargetInfo {
     }
 }
 
+/// Capacity of target in bytes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TargetCapacity {
+    /// Total bytes.
+    pub total: u64,
+    /// Used bytes.
+    pub used: u64,
+}
+
+impl TargetCapacity {
+    /// Usage recorded in cluster map is rounded to steps of `1 / USAGE_STEPS` of total.
+    pub const USAGE_STEPS: u64 = 20;
+
+    pub fn new(total: u64, used: u64) -> TargetCapacity {
+        TargetCapacity { total, used }
+    }
+
+    /// Usage rounded down to steps of `1 / USAGE_STEPS` of total.
+    pub fn usage_step(&self) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let used = self.used.min(self.total) as u128;
+        (used * Self::USAGE_STEPS as u128 / self.total as u128) as u64
+    }
+
+    /// Whether reported capacity `new` should replace recorded `self` in cluster map:
+    /// total changed or usage crossed a step.
+    pub fn needs_update(&self, new: &TargetCapacity) -> bool {
+        self.total != new.total || self.usage_step() != new.usage_step()
+    }
+
+    /// Free bytes.
+    pub fn free(&self) -> u64 {
+        self.total.saturating_sub(self.used)
+    }
+
+    /// Default weight for a target with this capacity: 1 per GiB, at least 1.
+    pub fn default_weight(&self) -> u32 {
+        (self.total >> 30).clamp(1, u32::MAX as u64) as u32
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 /// Target state
 /// - UP/DOWN: target is healthy(has a network url) or dead.
 /// - IN/OUT: we only assign data to in targets.
+/// - DRAIN: target keeps its data and is still readable, but receives no new placements,
+///   data is migrated out and the target is marked OUT after that.
 pub enum TargetState {
     /// Initial state, `(url, Option<id>)`
     Init(SocketAddr, Option<TargetId>),
@@ -118,6 +226,10 @@ pub enum TargetState {
     UpOut(TargetId, SocketAddr),
     /// Target is DOWN but OUT, `(id)`
     DownOut(TargetId),
+    /// Target is UP and DRAIN, `(id, url)`
+    UpDrain(TargetId, SocketAddr),
+    /// Target is DOWN and DRAIN, `(id)`
+    DownDrain(TargetId),
 }
 
 impl TargetState {
@@ -135,18 +247,26 @@ impl TargetState {
     }
 
     pub fn is_up(&self) -> bool {
-        matches!(self, TargetState::UpIn(_, _) | TargetState::UpOut(_, _))
+        matches!(
+            self,
+            TargetState::UpIn(_, _) | TargetState::UpOut(_, _) | TargetState::UpDrain(_, _)
+        )
     }
 
     pub fn is_in(&self) -> bool {
         matches!(self, TargetState::UpIn(_, _) | TargetState::DownIn(_))
     }
 
+    pub fn is_draining(&self) -> bool {
+        matches!(self, TargetState::UpDrain(_, _) | TargetState::DownDrain(_))
+    }
+
     pub fn get_url(&self) -> Option<&SocketAddr> {
         match self {
             TargetState::Init(url, _) => Some(url),
             TargetState::UpIn(_, url) => Some(url),
             TargetState::UpOut(_, url) => Some(url),
+            TargetState::UpDrain(_, url) => Some(url),
             _ => None,
         }
     }
@@ -157,87 +277,92 @@ impl TargetState {
             TargetState::DownIn(id) => Some(id),
             TargetState::UpOut(id, _) => Some(id),
             TargetState::DownOut(id) => Some(id),
+            TargetState::UpDrain(id, _) => Some(id),
+            TargetState::DownDrain(id) => Some(id),
             TargetState::Init(_, id) => id,
         }
     }
 
-    pub fn init(&self, id: TargetId, up: bool, in_: bool) -> TargetState {
+    /// Assign id to an Init target, `up` and `in_` decide the new state.
+    pub fn init(&self, id: TargetId, up: bool, in_: bool) -> Result<TargetState, TransitionError> {
         let url = match self {
-            TargetState::Init(url, _) => url.clone(),
-            _ => unreachable!(),
+            TargetState::Init(url, _) => *url,
+            _ => return Err(TransitionError::Illegal(self.to_str(), "init")),
         };
-        match (up, in_) {
-            (true, true) => TargetState::UpIn(id, url),
-            (true, false) => TargetState::UpOut(id, url),
-            (false, true) => TargetState::DownIn(id),
-            (false, false) => TargetState::DownOut(id),
-        }
+        let url = if up { Some(url) } else { None };
+        Ok(TargetState::new(id, url, in_))
     }
 
-    pub fn up(&self, url: SocketAddr) -> Option<TargetState> {
-        match self {
-            TargetState::UpIn(target_id, old_url) => {
-                if old_url == &url {
-                    None
-                } else {
-                    Some(TargetState::UpIn(*target_id, url))
-                }
+    /// Mark target UP with `url`, changing url of an UP target is allowed.
+    pub fn up(&self, url: SocketAddr) -> Result<TargetState, TransitionError> {
+        match *self {
+            TargetState::Init(_, _) => Err(TransitionError::Illegal(self.to_str(), "up")),
+            TargetState::UpIn(_, old_url)
+            | TargetState::UpOut(_, old_url)
+            | TargetState::UpDrain(_, old_url)
+                if old_url == url =>
+            {
+                Err(TransitionError::NoOp(self.to_str()))
             }
-            TargetState::UpOut(target_id, old_url) => {
-                if old_url == &url {
-                    None
-                } else {
-                    Some(TargetState::UpOut(*target_id, url))
-                }
+            TargetState::UpIn(id, _) | TargetState::DownIn(id) => Ok(TargetState::UpIn(id, url)),
+            TargetState::UpOut(id, _) | TargetState::DownOut(id) => Ok(TargetState::UpOut(id, url)),
+            TargetState::UpDrain(id, _) | TargetState::DownDrain(id) => {
+                Ok(TargetState::UpDrain(id, url))
             }
-            TargetState::DownIn(target_id) => Some(TargetState::UpIn(*target_id, url)),
-            TargetState::DownOut(target_id) => Some(TargetState::UpOut(*target_id, url)),
-            _ => unreachable!(),
         }
     }
 
-    pub fn down(&self) -> Option<TargetState> {
-        if !self.is_up() {
-            None
-        } else {
-            let target_id = self.get_id().unwrap();
-            if self.is_in() {
-                Some(TargetState::DownIn(target_id))
-            } else {
-                Some(TargetState::DownOut(target_id))
+    pub fn down(&self) -> Result<TargetState, TransitionError> {
+        match *self {
+            TargetState::Init(_, _) => Err(TransitionError::Illegal(self.to_str(), "down")),
+            TargetState::DownIn(_) | TargetState::DownOut(_) | TargetState::DownDrain(_) => {
+                Err(TransitionError::NoOp(self.to_str()))
             }
+            TargetState::UpIn(id, _) => Ok(TargetState::DownIn(id)),
+            TargetState::UpOut(id, _) => Ok(TargetState::DownOut(id)),
+            TargetState::UpDrain(id, _) => Ok(TargetState::DownDrain(id)),
         }
     }
 
-    pub fn add_in(&self) -> Option<TargetState> {
-        if self.is_in() {
-            None
-        } else {
-            let target_id = self.get_id().unwrap();
-            if self.is_up() {
-                Some(TargetState::UpIn(
-                    target_id,
-                    self.get_url().unwrap().to_owned(),
-                ))
-            } else {
-                Some(TargetState::DownIn(target_id))
+    /// Mark target IN, a DRAIN target can be added back.
+    pub fn add_in(&self) -> Result<TargetState, TransitionError> {
+        match *self {
+            TargetState::Init(_, _) => Err(TransitionError::Illegal(self.to_str(), "add_in")),
+            TargetState::UpIn(_, _) | TargetState::DownIn(_) => {
+                Err(TransitionError::NoOp(self.to_str()))
             }
+            TargetState::UpOut(id, url) | TargetState::UpDrain(id, url) => {
+                Ok(TargetState::UpIn(id, url))
+            }
+            TargetState::DownOut(id) | TargetState::DownDrain(id) => Ok(TargetState::DownIn(id)),
         }
     }
 
-    pub fn remove_out(&self) -> Option<TargetState> {
-        if !self.is_in() {
-            None
-        } else {
-            let target_id = self.get_id().unwrap();
-            if self.is_up() {
-                Some(TargetState::UpOut(
-                    target_id,
-                    self.get_url().unwrap().to_owned(),
-                ))
-            } else {
-                Some(TargetState::DownOut(target_id))
+    /// Mark target OUT, both IN and DRAIN targets can be removed.
+    pub fn remove_out(&self) -> Result<TargetState, TransitionError> {
+        match *self {
+            TargetState::Init(_, _) => Err(TransitionError::Illegal(self.to_str(), "remove_out")),
+            TargetState::UpOut(_, _) | TargetState::DownOut(_) => {
+                Err(TransitionError::NoOp(self.to_str()))
             }
+            TargetState::UpIn(id, url) | TargetState::UpDrain(id, url) => {
+                Ok(TargetState::UpOut(id, url))
+            }
+            TargetState::DownIn(id) | TargetState::DownDrain(id) => Ok(TargetState::DownOut(id)),
+        }
+    }
+
+    /// Stop assigning new data to an IN target.
+    pub fn drain(&self) -> Result<TargetState, TransitionError> {
+        match *self {
+            TargetState::Init(_, _) | TargetState::UpOut(_, _) | TargetState::DownOut(_) => {
+                Err(TransitionError::Illegal(self.to_str(), "drain"))
+            }
+            TargetState::UpDrain(_, _) | TargetState::DownDrain(_) => {
+                Err(TransitionError::NoOp(self.to_str()))
+            }
+            TargetState::UpIn(id, url) => Ok(TargetState::UpDrain(id, url)),
+            TargetState::DownIn(id) => Ok(TargetState::DownDrain(id)),
         }
     }
 
@@ -248,47 +373,475 @@ impl TargetState {
             Self::DownIn(_) => "DownIn",
             Self::UpOut(_, _) => "UpOut",
             Self::DownOut(_) => "DownOut",
+            Self::UpDrain(_, _) => "UpDrain",
+            Self::DownDrain(_) => "DownDrain",
+        }
+    }
+}
+
+/// Failure domain levels of a target location, from the widest to the narrowest.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FailureDomain {
+    Region,
+    Zone,
+    Rack,
+    Host,
+    Device,
+}
+
+impl FailureDomain {
+    /// All levels, from the widest to the narrowest.
+    pub const ALL: [FailureDomain; 5] = [
+        FailureDomain::Region,
+        FailureDomain::Zone,
+        FailureDomain::Rack,
+        FailureDomain::Host,
+        FailureDomain::Device,
+    ];
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Region => "Region",
+            Self::Zone => "Zone",
+            Self::Rack => "Rack",
+            Self::Host => "Host",
+            Self::Device => "Device",
         }
     }
 }
 
+impl FromStr for FailureDomain {
+    type Err = crate::Error;
+
+    /// Parse level name, case insensitive, eg: `rack`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FailureDomain::ALL
+            .iter()
+            .find(|level| level.to_str().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or(crate::Error::InvalidArg)
+    }
+}
+
+/// Error of target state transition.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TransitionError {
+    /// Target is already in requested state, `(state)`
+    #[error("target is already {0}")]
+    NoOp(&'static str),
+    /// Transition is not allowed from current state, `(state, op)`
+    #[error("can't {1} target in state {0}")]
+    Illegal(&'static str, &'static str),
+}
+
+impl TransitionError {
+    pub fn is_noop(&self) -> bool {
+        matches!(self, TransitionError::NoOp(_))
+    }
+}
+
 /// Target location.
 ///
-/// todo: may be should support host_id, rack_id, etc...
-/// todo: handle location change in future
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// A target declares its location when it registers,
+/// each level is a failure domain that contains all levels below it.
+/// Location can be changed later by a move changelog.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TargetLocation {
+    pub region: String,
+    pub zone: String,
+    pub rack: String,
     pub host: String,
+    pub device: String,
 }
 
 impl TargetLocation {
-    fn new() -> TargetLocation {
+    /// Name used for levels that are not specified.
+    pub const DEFAULT_DOMAIN: &'static str = "default";
+
+    /// Location with name of each level.
+    ///
+    /// Return `Error::InvalidArg` if a name is empty or contains `/`, names are joined
+    /// by `/` in paths, distinct domains must have distinct paths that parse back.
+    pub fn new(
+        region: &str,
+        zone: &str,
+        rack: &str,
+        host: &str,
+        device: &str,
+    ) -> Result<TargetLocation, crate::Error> {
+        let names = [region, zone, rack, host, device];
+        if names
+            .iter()
+            .any(|name| name.is_empty() || name.contains('/'))
+        {
+            return Err(crate::Error::InvalidArg);
+        }
+        Ok(TargetLocation {
+            region: region.to_owned(),
+            zone: zone.to_owned(),
+            rack: rack.to_owned(),
+            host: host.to_owned(),
+            device: device.to_owned(),
+        })
+    }
+
+    /// Location with only host and device known, other levels are set to default.
+    pub fn with_host(host: &str, device: &str) -> Result<TargetLocation, crate::Error> {
+        let default = Self::DEFAULT_DOMAIN;
+        TargetLocation::new(default, default, default, host, device)
+    }
+
+    /// Location of the local host, use hostname as host.
+    pub fn local(device: &str) -> Result<TargetLocation, crate::Error> {
         let hostname = gethostname::gethostname().to_str().unwrap().to_owned();
-        TargetLocation { host: hostname }
+        TargetLocation::with_host(&hostname, device)
+    }
+
+    /// Get name of the failure domain at `level`.
+    pub fn domain(&self, level: FailureDomain) -> &str {
+        match level {
+            FailureDomain::Region => &self.region,
+            FailureDomain::Zone => &self.zone,
+            FailureDomain::Rack => &self.rack,
+            FailureDomain::Host => &self.host,
+            FailureDomain::Device => &self.device,
+        }
     }
 
+    /// Path from region down to `level`, eg: `/region/zone/rack`.
+    ///
+    /// Two targets are in the same failure domain at `level` iff their paths are equal.
+    pub fn domain_path(&self, level: FailureDomain) -> String {
+        FailureDomain::ALL
+            .iter()
+            .take_while(|&&l| l <= level)
+            .fold(String::new(), |path, &l| path + "/" + self.domain(l))
+    }
+
+    /// Full path of the location, eg: `/region/zone/rack/host/device`.
     pub fn format_path(&self) -> String {
-        format!("/{}", self.host)
+        self.domain_path(FailureDomain::Device)
+    }
+}
+
+impl Display for TargetLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.format_path())
     }
 }
 
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// pub struct TargetChange {
-//     pub uuid: uuid::Uuid,
-//     pub op: TargetChangeOp,
-// }
-
-// #[derive(Debug, Clone, Serialize, Deserialize)]
-// pub enum TargetChangeOp {
-//     // new target crated, (id, url, location)
-//     Creat(TargetId, String, TargetLocation),
-//     // target mark self as UP, (url)
-//     Up(String),
-//     // target DOWN
-//     Down,
-//     // target IN
-//     In,
-//     // target OUT
-//     Out,
-//     // todo: add more operation, eg: move location ...
-// }
+impl FromStr for TargetLocation {
+    type Err = crate::Error;
+
+    /// Parse full path of the location, eg: `/region/zone/rack/host/device`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let levels: Vec<&str> = s
+            .strip_prefix('/')
+            .ok_or(crate::Error::InvalidArg)?
+            .split('/')
+            .collect();
+        match levels[..] {
+            [region, zone, rack, host, device] => {
+                TargetLocation::new(region, zone, rack, host, device)
+            }
+            _ => Err(crate::Error::InvalidArg),
+        }
+    }
+}
+
+/// One operation on a target, recorded in changelog.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TargetChange {
+    pub uuid: Uuid,
+    pub op: TargetChangeOp,
+}
+
+impl TargetChange {
+    pub fn new(uuid: Uuid, op: TargetChangeOp) -> TargetChange {
+        TargetChange { uuid, op }
+    }
+}
+
+impl Display for TargetChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "target {}: ", self.uuid)?;
+        match &self.op {
+            TargetChangeOp::Create(id, Some(url), location, _) => {
+                write!(f, "create id {} url {} at {}", id, url, location)
+            }
+            TargetChangeOp::Create(id, None, location, _) => {
+                write!(f, "create id {} at {}", id, location)
+            }
+            TargetChangeOp::Up(url) => write!(f, "up {}", url),
+            TargetChangeOp::Move(location) => write!(f, "move to {}", location),
+            TargetChangeOp::SetWeight(weight) => write!(f, "set weight {}", weight),
+            TargetChangeOp::Capacity(capacity) => {
+                write!(f, "capacity {}/{}", capacity.used, capacity.total)
+            }
+            TargetChangeOp::Maintenance(on) => write!(f, "maintenance {}", on),
+            op => f.write_str(op.to_str()),
+        }
+    }
+}
+
+/// Operation on a target, new target state is derived when changelog is applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TargetChangeOp {
+    /// New target created OUT, `(id, url, location, capacity)`, DOWN if url is None.
+    Create(TargetId, Option<SocketAddr>, TargetLocation, TargetCapacity),
+    /// Target mark self as UP, `(url)`
+    Up(SocketAddr),
+    /// Target DOWN
+    Down,
+    /// Target IN
+    In,
+    /// Target OUT
+    Out,
+    /// Target DRAIN
+    Drain,
+    /// Target moved to new location
+    Move(TargetLocation),
+    /// Operator set placement weight
+    SetWeight(u32),
+    /// Target reports capacity
+    Capacity(TargetCapacity),
+    /// Target enters or leaves maintenance
+    Maintenance(bool),
+    /// Target removed from cluster map permanently, must be DownOut
+    Remove,
+}
+
+impl TargetChangeOp {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Create(..) => "create",
+            Self::Up(_) => "up",
+            Self::Down => "down",
+            Self::In => "in",
+            Self::Out => "out",
+            Self::Drain => "drain",
+            Self::Move(_) => "move",
+            Self::SetWeight(_) => "set_weight",
+            Self::Capacity(_) => "capacity",
+            Self::Maintenance(_) => "maintenance",
+            Self::Remove => "remove",
+        }
+    }
+
+    /// Major operations change data placement and bump major version of cluster map.
+    pub fn is_major(&self) -> bool {
+        match self {
+            Self::In
+            | Self::Out
+            | Self::Drain
+            | Self::Move(_)
+            | Self::SetWeight(_)
+            | Self::Remove => true,
+            Self::Create(..)
+            | Self::Up(_)
+            | Self::Down
+            | Self::Capacity(_)
+            | Self::Maintenance(_) => false,
+        }
+    }
+
+    /// Operations of the same kind on one target conflict with each other in one changelog,
+    /// eg: UP and DOWN, IN and OUT.
+    pub fn conflicts_with(&self, other: &TargetChangeOp) -> bool {
+        fn kind(op: &TargetChangeOp) -> u8 {
+            match op {
+                TargetChangeOp::Up(_) | TargetChangeOp::Down => 0,
+                TargetChangeOp::In | TargetChangeOp::Out | TargetChangeOp::Drain => 1,
+                TargetChangeOp::Create(..) => 2,
+                TargetChangeOp::Move(_) => 3,
+                TargetChangeOp::SetWeight(_) => 4,
+                TargetChangeOp::Capacity(_) => 5,
+                TargetChangeOp::Maintenance(_) => 6,
+                TargetChangeOp::Remove => 7,
+            }
+        }
+        kind(self) == kind(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Op {
+        Init(bool, bool),
+        Up,
+        UpNewUrl,
+        Down,
+        In,
+        Out,
+        Drain,
+    }
+
+    fn apply(state: &TargetState, op: Op) -> Result<TargetState, TransitionError> {
+        match op {
+            Op::Init(up, in_) => state.init(1, up, in_),
+            Op::Up => state.up(url1()),
+            Op::UpNewUrl => state.up(url2()),
+            Op::Down => state.down(),
+            Op::In => state.add_in(),
+            Op::Out => state.remove_out(),
+            Op::Drain => state.drain(),
+        }
+    }
+
+    fn url1() -> SocketAddr {
+        "10.0.0.1:8000".parse().unwrap()
+    }
+
+    fn url2() -> SocketAddr {
+        "10.0.0.2:8000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_transition_table() {
+        use TargetState::*;
+        let ok = |s: TargetState| Ok(s);
+        let noop = |s: &'static str| Err(TransitionError::NoOp(s));
+        let illegal = |s: &'static str, op: &'static str| Err(TransitionError::Illegal(s, op));
+
+        let init = Init(url1(), None);
+        let table: Vec<(TargetState, Op, Result<TargetState, TransitionError>)> = vec![
+            (init.clone(), Op::Init(true, true), ok(UpIn(1, url1()))),
+            (init.clone(), Op::Init(true, false), ok(UpOut(1, url1()))),
+            (init.clone(), Op::Init(false, true), ok(DownIn(1))),
+            (init.clone(), Op::Init(false, false), ok(DownOut(1))),
+            (init.clone(), Op::Up, illegal("Init", "up")),
+            (init.clone(), Op::UpNewUrl, illegal("Init", "up")),
+            (init.clone(), Op::Down, illegal("Init", "down")),
+            (init.clone(), Op::In, illegal("Init", "add_in")),
+            (init.clone(), Op::Out, illegal("Init", "remove_out")),
+            (init, Op::Drain, illegal("Init", "drain")),
+            (UpIn(1, url1()), Op::Init(true, true), illegal("UpIn", "init")),
+            (UpIn(1, url1()), Op::Up, noop("UpIn")),
+            (UpIn(1, url1()), Op::UpNewUrl, ok(UpIn(1, url2()))),
+            (UpIn(1, url1()), Op::Down, ok(DownIn(1))),
+            (UpIn(1, url1()), Op::In, noop("UpIn")),
+            (UpIn(1, url1()), Op::Out, ok(UpOut(1, url1()))),
+            (UpIn(1, url1()), Op::Drain, ok(UpDrain(1, url1()))),
+            (DownIn(1), Op::Init(true, true), illegal("DownIn", "init")),
+            (DownIn(1), Op::Up, ok(UpIn(1, url1()))),
+            (DownIn(1), Op::UpNewUrl, ok(UpIn(1, url2()))),
+            (DownIn(1), Op::Down, noop("DownIn")),
+            (DownIn(1), Op::In, noop("DownIn")),
+            (DownIn(1), Op::Out, ok(DownOut(1))),
+            (DownIn(1), Op::Drain, ok(DownDrain(1))),
+            (UpOut(1, url1()), Op::Init(true, true), illegal("UpOut", "init")),
+            (UpOut(1, url1()), Op::Up, noop("UpOut")),
+            (UpOut(1, url1()), Op::UpNewUrl, ok(UpOut(1, url2()))),
+            (UpOut(1, url1()), Op::Down, ok(DownOut(1))),
+            (UpOut(1, url1()), Op::In, ok(UpIn(1, url1()))),
+            (UpOut(1, url1()), Op::Out, noop("UpOut")),
+            (UpOut(1, url1()), Op::Drain, illegal("UpOut", "drain")),
+            (DownOut(1), Op::Init(true, true), illegal("DownOut", "init")),
+            (DownOut(1), Op::Up, ok(UpOut(1, url1()))),
+            (DownOut(1), Op::UpNewUrl, ok(UpOut(1, url2()))),
+            (DownOut(1), Op::Down, noop("DownOut")),
+            (DownOut(1), Op::In, ok(DownIn(1))),
+            (DownOut(1), Op::Out, noop("DownOut")),
+            (DownOut(1), Op::Drain, illegal("DownOut", "drain")),
+            (UpDrain(1, url1()), Op::Init(true, true), illegal("UpDrain", "init")),
+            (UpDrain(1, url1()), Op::Up, noop("UpDrain")),
+            (UpDrain(1, url1()), Op::UpNewUrl, ok(UpDrain(1, url2()))),
+            (UpDrain(1, url1()), Op::Down, ok(DownDrain(1))),
+            (UpDrain(1, url1()), Op::In, ok(UpIn(1, url1()))),
+            (UpDrain(1, url1()), Op::Out, ok(UpOut(1, url1()))),
+            (UpDrain(1, url1()), Op::Drain, noop("UpDrain")),
+            (DownDrain(1), Op::Init(true, true), illegal("DownDrain", "init")),
+            (DownDrain(1), Op::Up, ok(UpDrain(1, url1()))),
+            (DownDrain(1), Op::UpNewUrl, ok(UpDrain(1, url2()))),
+            (DownDrain(1), Op::Down, noop("DownDrain")),
+            (DownDrain(1), Op::In, ok(DownIn(1))),
+            (DownDrain(1), Op::Out, ok(DownOut(1))),
+            (DownDrain(1), Op::Drain, noop("DownDrain")),
+        ];
+
+        for (state, op, expect) in table {
+            assert_eq!(apply(&state, op), expect, "{:?} {:?}", state, op);
+        }
+    }
+
+    #[test]
+    fn test_failure_domain_parse() {
+        for level in FailureDomain::ALL {
+            assert_eq!(level.to_str().parse::<FailureDomain>().unwrap(), level);
+        }
+        assert_eq!(
+            "rack".parse::<FailureDomain>().unwrap(),
+            FailureDomain::Rack
+        );
+        assert_eq!(
+            "HOST".parse::<FailureDomain>().unwrap(),
+            FailureDomain::Host
+        );
+        assert!("".parse::<FailureDomain>().is_err());
+        assert!("datacenter".parse::<FailureDomain>().is_err());
+    }
+
+    #[test]
+    fn test_location() {
+        let location = TargetLocation::new("r1", "z1", "rack1", "host1", "dev1").unwrap();
+        assert_eq!(location.domain(FailureDomain::Rack), "rack1");
+        assert_eq!(location.domain(FailureDomain::Device), "dev1");
+        let paths = FailureDomain::ALL.map(|level| location.domain_path(level));
+        assert_eq!(
+            paths,
+            [
+                "/r1",
+                "/r1/z1",
+                "/r1/z1/rack1",
+                "/r1/z1/rack1/host1",
+                "/r1/z1/rack1/host1/dev1"
+            ]
+        );
+        assert_eq!(location.to_string(), location.format_path());
+        assert_eq!(
+            location.format_path().parse::<TargetLocation>().unwrap(),
+            location
+        );
+
+        let location = TargetLocation::with_host("host1", "dev1").unwrap();
+        assert_eq!(
+            location.format_path(),
+            "/default/default/default/host1/dev1"
+        );
+        // same host in different racks are different failure domains
+        let other = TargetLocation::new("default", "default", "rack1", "host1", "dev1").unwrap();
+        assert_eq!(
+            location.domain_path(FailureDomain::Zone),
+            other.domain_path(FailureDomain::Zone)
+        );
+        assert_ne!(
+            location.domain_path(FailureDomain::Host),
+            other.domain_path(FailureDomain::Host)
+        );
+
+        for path in [
+            "",
+            "/",
+            "r1/z1/rack1/host1/dev1",
+            "/r1/z1/rack1/host1",
+            "/r1/z1/rack1/host1/dev1/part1",
+            "/r1//rack1/host1/dev1",
+        ] {
+            assert!(path.parse::<TargetLocation>().is_err(), "{}", path);
+        }
+
+        // rack "a/b" host "c" would share host path with rack "a" host "b/c"
+        assert!(TargetLocation::new("r", "z", "a/b", "c", "dev").is_err());
+        assert!(TargetLocation::new("r", "z", "a", "b/c", "dev").is_err());
+        assert!(TargetLocation::new("r", "z", "", "host", "dev").is_err());
+        assert!(TargetLocation::with_host("/", "dev").is_err());
+        let location = TargetLocation::new("r 1", "z-1", "..", "host.example:80", "ü").unwrap();
+        assert_eq!(
+            location.to_string().parse::<TargetLocation>().unwrap(),
+            location
+        );
+    }
+}
@@ -1,25 +1,231 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::{ClusterMap, ClusterMapVersion, Error};
+use crate::{
+    ClusterMap, ClusterMapDiff, ClusterMapVersion, Compare, Error, KvPage, KvWatcher, LeaseId,
+    ObjectClass, OcType, TargetCapacity, TargetId, TxnOp, TxnResponse, KV_GET_ALL_LIMIT,
+};
 use async_trait::async_trait;
 
 #[async_trait]
 pub trait Conf: Send + Sync {
+    /// Create chunk type, reuse id of deleted types, `Error::MaxChunkType` if all ids are used.
     async fn oc_create_chunk_type(&self, chunk_size: u32) -> Result<u8, Error>;
 
+    /// Create stripe type, reuse id of deleted types, `Error::MaxStripeType` if all ids are used.
     async fn oc_create_stripe_type(&self, stripe_cnt: u32) -> Result<u8, Error>;
 
+    /// Get stripe count and chunk size for a new object.
+    ///
+    /// Return `Error::OcTypeDeprecated` if either type is deprecated.
     async fn oc_get(&self, stripe_type: u8, chunk_size: u8) -> Result<Option<(u32, u32)>, Error>;
 
-    async fn oc_get_stripe(&self, stripe_type: u8) -> Result<Option<u32>, Error>;
+    /// Get stripe count and chunk size for a new object and count it as a user of both types.
+    ///
+    /// Return `Error::OcTypeNotFound` or `Error::OcTypeDeprecated` if either type can't be used.
+    async fn oc_acquire(&self, stripe_type: u8, chunk_type: u8) -> Result<(u32, u32), Error>;
+
+    /// Release types of a deleted object acquired by `oc_acquire`.
+    async fn oc_release(&self, stripe_type: u8, chunk_type: u8) -> Result<(), Error>;
+
+    /// Get stripe type, including deprecated ones still used by existing objects.
+    async fn oc_get_stripe(&self, stripe_type: u8) -> Result<Option<OcType>, Error>;
+
+    /// Get chunk type, including deprecated ones still used by existing objects.
+    async fn oc_get_chunk(&self, chunk_type: u8) -> Result<Option<OcType>, Error>;
+
+    /// Mark chunk type deprecated, no new objects may use it.
+    async fn oc_deprecate_chunk_type(&self, chunk_type: u8) -> Result<(), Error>;
+
+    /// Mark stripe type deprecated, no new objects may use it.
+    async fn oc_deprecate_stripe_type(&self, stripe_type: u8) -> Result<(), Error>;
+
+    /// Delete deprecated chunk type and free its id.
+    ///
+    /// Return `Error::OcTypeNotDeprecated` if it is not deprecated,
+    /// `Error::OcTypeInUse` if some objects haven't released it.
+    async fn oc_delete_chunk_type(&self, chunk_type: u8) -> Result<(), Error>;
+
+    /// Delete deprecated stripe type and free its id.
+    ///
+    /// Return `Error::OcTypeNotDeprecated` if it is not deprecated,
+    /// `Error::OcTypeInUse` if some objects haven't released it.
+    async fn oc_delete_stripe_type(&self, stripe_type: u8) -> Result<(), Error>;
+
+    /// Create object class `class` after validating it against current cluster map of controller.
+    ///
+    /// Return `Error::OcClassExists` if a class with the same name exists.
+    async fn oc_create_class(&self, class: &ObjectClass) -> Result<(), Error>;
+
+    /// Get object class `name`.
+    async fn oc_get_class(&self, name: &str) -> Result<Option<ObjectClass>, Error>;
+
+    /// Get value of `key` and its mod revision.
+    async fn kv_get_bytes(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, Error>;
+
+    /// Put `key`, return its new mod revision.
+    async fn kv_put_bytes(&self, key: &[u8], value: &[u8]) -> Result<u64, Error>;
 
-    async fn oc_get_chunk(&self, chunk_type: u8) -> Result<Option<u32>, Error>;
+    /// Delete `key`, return false if it doesn't exist.
+    async fn kv_delete_bytes(&self, key: &[u8]) -> Result<bool, Error>;
+
+    /// Put `key` if its mod revision is `expected_revision`, 0 means `key` must not exist.
+    ///
+    /// Return new mod revision, or `Error::TxnConflict` if revision mismatches.
+    async fn kv_cas_bytes(
+        &self,
+        key: &[u8],
+        expected_revision: u64,
+        value: &[u8],
+    ) -> Result<u64, Error>;
+
+    /// Run `success` ops if all `compares` hold, otherwise `failure` ops, atomically.
+    ///
+    /// Return `Error::TxnConflict` if compares fail and `failure` is empty.
+    async fn txn(
+        &self,
+        compares: Vec<Compare>,
+        success: Vec<TxnOp>,
+        failure: Vec<TxnOp>,
+    ) -> Result<TxnResponse, Error>;
+
+    /// Watch puts and deletes of keys with `prefix`, from events at or after `from_revision`.
+    ///
+    /// `from_revision` 0 means only new events. Resume with `KvWatcher::next_revision`
+    /// after reconnecting, `Error::RevisionCompacted` if events since then are dropped.
+    async fn kv_watch_bytes(&self, prefix: &[u8], from_revision: u64) -> Result<KvWatcher, Error>;
+
+    /// Scan keys with `prefix` from `start` in key order, at most `limit` keys.
+    ///
+    /// Pass `KvPage::next` as `start` to get the next page.
+    async fn kv_scan_bytes(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<(Vec<u8>, Vec<u8>), Vec<u8>>, Error>;
+
+    /// Same as `kv_scan_bytes`, but only return keys.
+    async fn kv_scan_keys_bytes(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+        limit: usize,
+    ) -> Result<KvPage<Vec<u8>, Vec<u8>>, Error>;
+
+    /// Count keys with `prefix`.
+    async fn kv_count_bytes(&self, prefix: &[u8]) -> Result<u64, Error>;
+
+    /// Grant a lease, it expires if not kept alive within `ttl`.
+    ///
+    /// Keys attached to the lease are deleted when it expires or is revoked.
+    async fn lease_grant(&self, ttl: Duration) -> Result<LeaseId, Error>;
 
-    async fn kv_get(&self, key: &str) -> Result<Option<String>, Error>;
+    /// Keep lease `id` alive for another ttl, `Error::LeaseNotFound` if already expired.
+    async fn lease_keep_alive(&self, id: LeaseId) -> Result<(), Error>;
 
-    async fn kv_put(&self, key: &str, value: &str) -> Result<(), Error>;
+    /// Revoke lease `id` and delete keys attached to it, return number of deleted keys.
+    async fn lease_revoke(&self, id: LeaseId) -> Result<usize, Error>;
 
-    async fn kv_get_all(&self, prefix: &str) -> Result<Vec<(String, String)>, Error>;
+    /// Put `key` and attach it to lease `id`, return its new mod revision.
+    ///
+    /// A later put without lease detaches the key.
+    async fn kv_put_lease_bytes(&self, key: &[u8], value: &[u8], id: LeaseId)
+        -> Result<u64, Error>;
+
+    /// String version of `kv_get_bytes`, `Error::InvalidArg` if value is not UTF-8.
+    async fn kv_get(&self, key: &str) -> Result<Option<(String, u64)>, Error> {
+        match self.kv_get_bytes(key.as_bytes()).await? {
+            Some((value, revision)) => Ok(Some((utf8(value)?, revision))),
+            None => Ok(None),
+        }
+    }
+
+    /// String version of `kv_put_bytes`.
+    async fn kv_put(&self, key: &str, value: &str) -> Result<u64, Error> {
+        self.kv_put_bytes(key.as_bytes(), value.as_bytes()).await
+    }
+
+    /// String version of `kv_put_lease_bytes`.
+    async fn kv_put_lease(&self, key: &str, value: &str, id: LeaseId) -> Result<u64, Error> {
+        self.kv_put_lease_bytes(key.as_bytes(), value.as_bytes(), id)
+            .await
+    }
+
+    /// String version of `kv_delete_bytes`.
+    async fn kv_delete(&self, key: &str) -> Result<bool, Error> {
+        self.kv_delete_bytes(key.as_bytes()).await
+    }
+
+    /// String version of `kv_cas_bytes`.
+    async fn kv_cas(&self, key: &str, expected_revision: u64, value: &str) -> Result<u64, Error> {
+        self.kv_cas_bytes(key.as_bytes(), expected_revision, value.as_bytes())
+            .await
+    }
+
+    /// String version of `kv_watch_bytes`.
+    async fn kv_watch(&self, prefix: &str, from_revision: u64) -> Result<KvWatcher, Error> {
+        self.kv_watch_bytes(prefix.as_bytes(), from_revision).await
+    }
+
+    /// String version of `kv_scan_bytes`, `Error::InvalidArg` if any key or value is not UTF-8.
+    async fn kv_scan(
+        &self,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<KvPage<(String, String), String>, Error> {
+        let page = self
+            .kv_scan_bytes(prefix.as_bytes(), start.as_bytes(), limit)
+            .await?;
+        let items = page
+            .items
+            .into_iter()
+            .map(|(key, value)| Ok((utf8(key)?, utf8(value)?)))
+            .collect::<Result<_, Error>>()?;
+        let next = page.next.map(utf8).transpose()?;
+        Ok(KvPage { items, next })
+    }
+
+    /// String version of `kv_scan_keys_bytes`.
+    async fn kv_scan_keys(
+        &self,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<KvPage<String, String>, Error> {
+        let page = self
+            .kv_scan_keys_bytes(prefix.as_bytes(), start.as_bytes(), limit)
+            .await?;
+        let items = page
+            .items
+            .into_iter()
+            .map(utf8)
+            .collect::<Result<_, Error>>()?;
+        let next = page.next.map(utf8).transpose()?;
+        Ok(KvPage { items, next })
+    }
+
+    /// String version of `kv_count_bytes`.
+    async fn kv_count(&self, prefix: &str) -> Result<u64, Error> {
+        self.kv_count_bytes(prefix.as_bytes()).await
+    }
+
+    /// Get all keys with `prefix`.
+    ///
+    /// Return `Error::TooManyKeys` if there are more than `KV_GET_ALL_LIMIT` keys,
+    /// use `kv_scan` instead for large prefixes.
+    async fn kv_get_all(&self, prefix: &str) -> Result<Vec<(String, String)>, Error> {
+        let page = self.kv_scan(prefix, prefix, KV_GET_ALL_LIMIT).await?;
+        if page.next.is_some() {
+            return Err(Error::TooManyKeys(KV_GET_ALL_LIMIT));
+        }
+        Ok(page.items)
+    }
+}
+
+fn utf8(buf: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(buf).map_err(|_| Error::InvalidArg)
 }
 
 #[async_trait]
@@ -27,6 +233,13 @@ pub trait ServerCtl: Send + Sync {
     /// Wait a new cluster map.
     async fn wait_map(&self, prev_version: ClusterMapVersion) -> Arc<ClusterMap>;
 
+    /// Wait a new cluster map, return it with changes since `prev`.
+    async fn wait_changes(&self, prev: Arc<ClusterMap>) -> (Arc<ClusterMap>, ClusterMapDiff) {
+        let map = self.wait_map(prev.version).await;
+        let diff = prev.diff(&map);
+        (map, diff)
+    }
+
     /// Close server, stop background task and mark self as DOWN.
     async fn close(&self);
 
@@ -34,13 +247,25 @@ pub trait ServerCtl: Send + Sync {
     fn current_map(&self) -> Arc<ClusterMap>;
 
     /// Update cluster map if we know new version exists.
+    ///
+    /// Map is applied by local replica, which resumes applying raft log
+    /// after its persisted revision on restart.
     async fn update_map(&self) -> Result<Arc<ClusterMap>, Error>;
 
     /// Get conf client.
     fn get_conf(&self) -> &dyn Conf;
 
     /// Alloc some unique oid.
+    ///
+    /// Return oids `[start, end)`, unique cluster-wide and never reused,
+    /// `Error::OidExhausted` if oid counter overflows.
     async fn oid_alloc(&self, cnt: u64) -> Result<(u64, u64), Error>;
+
+    /// Report capacity of self, called at registration and on heartbeat.
+    ///
+    /// Capacity is recorded in cluster map only if total changes or usage crosses
+    /// a step of `TargetCapacity::USAGE_STEPS`, so heartbeats don't bump map version.
+    async fn report_capacity(&self, capacity: TargetCapacity) -> Result<(), Error>;
 }
 
 #[async_trait]
@@ -48,7 +273,10 @@ pub trait ClientCtl: Send + Sync {
     /// Get current cluster map.
     fn current_map(&self) -> Arc<ClusterMap>;
 
-    /// Update cluster map.
+    /// Update cluster map until it reaches `version_hit`, or once if None.
+    ///
+    /// Resume from version of current map, only changelogs after it are fetched,
+    /// or full map if they are no longer retained on controller.
     async fn update_map(
         &self,
         version_hit: Option<ClusterMapVersion>,
@@ -61,4 +289,27 @@ pub trait ClientCtl: Send + Sync {
     ///
     /// Used in create fs, cnt_hint can use to check if all servers booted.
     async fn add_all_targets(&self, cnt_hint: Option<u32>) -> Result<Arc<ClusterMap>, Error>;
+
+    /// Set placement weight of target `id`.
+    async fn set_target_weight(&self, id: TargetId, weight: u32) -> Result<Arc<ClusterMap>, Error>;
+
+    /// Mark target `id` DRAIN, it stays readable but receives no new data.
+    ///
+    /// Reads find stripes with `ClusterMap::locate`, writes with `ClusterMap::place`.
+    async fn drain_target(&self, id: TargetId) -> Result<Arc<ClusterMap>, Error>;
+
+    /// Enter or leave maintenance mode for all targets under location `path`.
+    ///
+    /// Targets in maintenance are not marked DOWN/OUT automatically.
+    async fn set_maintenance(&self, path: &str, maintenance: bool) -> Result<Arc<ClusterMap>, Error>;
+
+    /// Remove DownOut target `id` from cluster map permanently.
+    ///
+    /// Operator should confirm all data on the target has been migrated.
+    async fn remove_target(&self, id: TargetId) -> Result<Arc<ClusterMap>, Error>;
+
+    /// Get cluster map of `version` from retained history on controller.
+    ///
+    /// Return None if `version` is older than retained history or never exists.
+    async fn map_at(&self, version: ClusterMapVersion) -> Result<Option<Arc<ClusterMap>>, Error>;
 }